@@ -0,0 +1,10 @@
+-- Users of the v1 API. `check_schema` and the readiness check verify that the
+-- migrations in this directory have been applied, in the order of their numbers.
+CREATE TABLE `users` (
+    `id` BIGINT UNSIGNED NOT NULL AUTO_INCREMENT,
+    `username` VARCHAR(255) NOT NULL,
+    `password` VARCHAR(255) NOT NULL,
+    `age` SMALLINT UNSIGNED NOT NULL,
+    `address` VARCHAR(255) NOT NULL,
+    PRIMARY KEY (`id`)
+) ENGINE = InnoDB DEFAULT CHARSET = utf8mb4 COLLATE = utf8mb4_0900_ai_ci;
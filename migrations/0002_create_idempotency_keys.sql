@@ -0,0 +1,15 @@
+-- Responses to the first requests with an idempotency key, replayed to their
+-- retries. The primary key makes a concurrent insert of the same key fail
+-- instead of overwriting the stored response.
+CREATE TABLE `idempotency_keys` (
+    `key` VARCHAR(255) CHARACTER SET ascii COLLATE ascii_bin NOT NULL,
+    -- SHA-256 of the operation and the payload, in hex.
+    `fingerprint` CHAR(64) CHARACTER SET ascii NOT NULL,
+    `status` SMALLINT UNSIGNED NOT NULL,
+    -- JSON array of the name and value pairs.
+    `headers` TEXT NOT NULL,
+    `body` MEDIUMBLOB NOT NULL,
+    `expires_at` DATETIME(6) NOT NULL,
+    PRIMARY KEY (`key`),
+    KEY `idempotency_keys_expires_at` (`expires_at`)
+) ENGINE = InnoDB DEFAULT CHARSET = utf8mb4 COLLATE = utf8mb4_0900_ai_ci;
@@ -0,0 +1,4 @@
+-- Version of a user for the optimistic concurrency control of the v2 API,
+-- sent as the `ETag` header. The existing users start from 1 as the new ones do.
+ALTER TABLE `users`
+    ADD COLUMN `version` BIGINT UNSIGNED NOT NULL DEFAULT 1;
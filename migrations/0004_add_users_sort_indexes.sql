@@ -0,0 +1,5 @@
+-- Serve the keyset pagination of the v2 list sorted by the username or the age,
+-- which seeks to (`username`, `id`) or (`age`, `id`) of the last listed user.
+ALTER TABLE `users`
+    ADD INDEX `users_username_id` (`username`, `id`),
+    ADD INDEX `users_age_id` (`age`, `id`);
@@ -0,0 +1,5 @@
+-- Serves the v2 search, which matches the words of the usernames and the
+-- addresses in boolean mode. `innodb_ft_min_token_size` (3 by default) is the
+-- shortest word that can be found, and the stopwords are never found.
+ALTER TABLE `users`
+    ADD FULLTEXT INDEX `users_username_address` (`username`, `address`);
@@ -0,0 +1,6 @@
+-- Soft deletion of the users. The v2 API hides the users with `deleted_at` set
+-- until they are restored or purged after the retention period, which the
+-- index lets the purge find in batches.
+ALTER TABLE `users`
+    ADD COLUMN `deleted_at` DATETIME(6) NULL DEFAULT NULL,
+    ADD INDEX `users_deleted_at` (`deleted_at`);
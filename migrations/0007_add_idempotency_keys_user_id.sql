@@ -0,0 +1,6 @@
+-- User created by the request with the idempotency key, so that purging the
+-- user deletes its stored response too. Not a foreign key, as the users are
+-- deleted without looking the keys up otherwise.
+ALTER TABLE `idempotency_keys`
+    ADD COLUMN `user_id` BIGINT UNSIGNED NULL DEFAULT NULL,
+    ADD INDEX `idempotency_keys_user_id` (`user_id`);
@@ -1,8 +1,12 @@
+use crate::core::secret::Secret;
+
+use std::collections::HashMap;
 use std::fmt;
 use std::fs::File;
 use std::io::Read;
+use std::net::{IpAddr, Ipv4Addr};
 
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
 use serde::de::{self, Visitor};
 use serde::{Deserialize, Deserializer};
 
@@ -13,12 +17,17 @@ pub struct Configuration {
     pub log: Log,
     pub database: Database,
     pub http: HTTP,
+    pub telemetry: Option<Telemetry>,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct Log {
     #[serde(deserialize_with = "deserialize_log_level")]
     pub level: log::LevelFilter,
+    /// Regular expressions whose matches are replaced with `***` in every log
+    /// line. If a pattern has capture groups, only the captured parts are replaced.
+    #[serde(default)]
+    pub redact_patterns: Vec<String>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -27,15 +36,605 @@ pub struct Database {
     pub host: String,
     pub port: u16,
     pub username: String,
-    pub password: String,
+    pub password: Secret<String>,
     pub name: String,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct HTTP {
-    pub port: u16,
-    pub tls_cert_file: String,
-    pub tls_key_file: String,
+    /// Required unless the deprecated `port` is set.
+    #[serde(default)]
+    pub listeners: Vec<Listener>,
+    /// Deprecated, use `listeners`. Serves the API on this port, with TLS if
+    /// `tls_cert_file` and `tls_key_file` are set.
+    pub port: Option<u16>,
+    pub tls_cert_file: Option<String>,
+    pub tls_key_file: Option<String>,
+    #[serde(default)]
+    pub health: Health,
+    /// Seconds to wait for the in-flight requests on shutdown.
+    #[serde(default = "default_grace_period")]
+    pub grace_period: u64,
+    /// Serves Swagger UI at `/docs` along with the API routes.
+    #[serde(default)]
+    pub openapi_ui: bool,
+    /// Writes a line per request if set.
+    pub access_log: Option<AccessLog>,
+    /// Limits the rate of the API requests per client if set.
+    pub rate_limit: Option<RateLimit>,
+    #[serde(default)]
+    pub limits: Limits,
+    /// CORS policy per route group. No CORS header is sent for the other groups.
+    #[serde(default)]
+    pub cors: HashMap<RouteGroup, Cors>,
+    /// Compresses the API responses and accepts compressed request bodies if set.
+    pub compression: Option<Compression>,
+    #[serde(default)]
+    pub idempotency: Idempotency,
+    #[serde(default)]
+    pub pagination: Pagination,
+    #[serde(default)]
+    pub retention: Retention,
+}
+
+fn default_grace_period() -> u64 {
+    30
+}
+
+impl HTTP {
+    // Turns the deprecated keys into a listener, so that the configurations
+    // written before `listeners` keep working.
+    fn migrate_legacy_listener(&mut self) -> Result<()> {
+        let Some(port) = self.port else {
+            if self.tls_cert_file.is_some() || self.tls_key_file.is_some() {
+                return Err(anyhow!(
+                    "http.tls_cert_file and tls_key_file require http.port"
+                ));
+            }
+            return Ok(());
+        };
+        if !self.listeners.is_empty() {
+            return Err(anyhow!(
+                "either http.listeners or http.port can be set, not both"
+            ));
+        }
+        let tls = match (self.tls_cert_file.take(), self.tls_key_file.take()) {
+            (Some(cert_file), Some(key_file)) => Some(TLS::new(cert_file, key_file)),
+            (None, None) => None,
+            _ => {
+                return Err(anyhow!(
+                    "http.tls_cert_file and tls_key_file have to be set together"
+                ))
+            }
+        };
+        self.listeners.push(Listener {
+            address: default_listener_address(),
+            port: Some(port),
+            unix: None,
+            tls,
+            routes: default_route_groups(),
+            redirect_to: None,
+        });
+        Ok(())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AccessLog {
+    #[serde(default)]
+    pub format: AccessLogFormat,
+    /// File to append the lines to, or `stdout` or `stderr`. The application log
+    /// is written to stdout, so the default is a file of its own.
+    #[serde(default = "default_access_log_path")]
+    pub path: String,
+    /// Fraction of the requests to log. Server errors are always logged.
+    #[serde(default = "default_access_log_sample_rate")]
+    pub sample_rate: f64,
+    /// Paths not to log, e.g., those of the health checks and the metrics.
+    #[serde(default)]
+    pub exclude_paths: Vec<String>,
+}
+
+fn default_access_log_path() -> String {
+    String::from("/var/log/rust_base/access.log")
+}
+
+fn default_access_log_sample_rate() -> f64 {
+    1.0
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AccessLogFormat {
+    /// NCSA Combined Log Format.
+    #[default]
+    Combined,
+    /// NCSA Common Log Format.
+    Common,
+    /// A JSON object per line, with the latency and the request id as well.
+    Json,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RateLimit {
+    #[serde(default)]
+    pub key: RateLimitKey,
+    /// Header with the API key if `key` is `api_key`.
+    #[serde(default = "default_api_key_header")]
+    pub api_key_header: String,
+    /// Limit of the routes without their own one. Unlimited if not set.
+    pub default: Option<Limit>,
+    #[serde(default)]
+    pub routes: Vec<RouteLimit>,
+}
+
+fn default_api_key_header() -> String {
+    String::from("x-api-key")
+}
+
+/// What identifies a client. Falls back to the IP address if the request has
+/// no API key or client certificate.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RateLimitKey {
+    #[default]
+    Ip,
+    /// API key together with the IP address, as the key isn't validated.
+    ApiKey,
+    /// Subject of the client certificate.
+    User,
+}
+
+/// Token bucket that allows `requests` per `period` on average, and up to
+/// `burst` at once.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Limit {
+    pub requests: u32,
+    /// In seconds.
+    #[serde(default = "default_limit_period")]
+    pub period: u64,
+    /// `requests` if not set.
+    pub burst: Option<u32>,
+}
+
+fn default_limit_period() -> u64 {
+    1
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RouteLimit {
+    /// Route as registered, e.g., `/api/v2/users/:id`.
+    pub path: String,
+    /// All methods if not set.
+    pub method: Option<String>,
+    #[serde(flatten)]
+    pub limit: Limit,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Cors {
+    /// Origins such as `https://admin.example.com`. `*` matches any part of a
+    /// host name, e.g., `https://*.example.com`.
+    pub allowed_origins: Vec<String>,
+    /// `*` allows all the methods the browser asks for.
+    #[serde(default = "default_cors_allowed_methods")]
+    pub allowed_methods: Vec<String>,
+    /// `*` allows all the headers the browser asks for.
+    #[serde(default = "default_cors_allowed_headers")]
+    pub allowed_headers: Vec<String>,
+    /// Response headers readable by the scripts.
+    #[serde(default = "default_cors_exposed_headers")]
+    pub exposed_headers: Vec<String>,
+    /// Allows cookies and client certificates.
+    #[serde(default)]
+    pub allow_credentials: bool,
+    /// Seconds the browsers may cache the result of a preflight request.
+    pub max_age: Option<u64>,
+}
+
+fn default_cors_allowed_methods() -> Vec<String> {
+    ["GET", "POST", "PATCH", "DELETE"]
+        .into_iter()
+        .map(String::from)
+        .collect()
+}
+
+fn default_cors_allowed_headers() -> Vec<String> {
+    vec![String::from("content-type"), String::from("x-request-id")]
+}
+
+fn default_cors_exposed_headers() -> Vec<String> {
+    vec![String::from("x-request-id"), String::from("location")]
+}
+
+/// Protects the server from slow and large API requests.
+#[derive(Debug, Deserialize)]
+pub struct Limits {
+    /// Seconds to wait for a response before returning 504. The transaction of
+    /// the request is rolled back.
+    #[serde(default = "default_request_timeout")]
+    pub timeout: u64,
+    /// Bytes of a request body, larger ones are rejected with 413.
+    #[serde(default = "default_max_body_size")]
+    pub max_body_size: usize,
+    /// Requests served at once, more are rejected with 503. Unlimited if not set.
+    pub max_concurrent_requests: Option<usize>,
+    /// Overrides for some routes. Their requests count towards the global
+    /// `max_concurrent_requests` as well.
+    #[serde(default)]
+    pub routes: Vec<RouteLimits>,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            timeout: default_request_timeout(),
+            max_body_size: default_max_body_size(),
+            max_concurrent_requests: None,
+            routes: Vec::new(),
+        }
+    }
+}
+
+fn default_request_timeout() -> u64 {
+    30
+}
+
+fn default_max_body_size() -> usize {
+    1024 * 1024
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RouteLimits {
+    /// Route as registered, e.g., `/api/v2/users/:id`.
+    pub path: String,
+    /// All methods if not set.
+    pub method: Option<String>,
+    pub timeout: Option<u64>,
+    pub max_body_size: Option<usize>,
+    pub max_concurrent_requests: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Compression {
+    /// Encodings to negotiate with `Accept-Encoding` and to accept in
+    /// `Content-Encoding`.
+    #[serde(default = "default_compression_algorithms")]
+    pub algorithms: Vec<CompressionAlgorithm>,
+    /// Bytes of a response body, smaller ones are sent as is.
+    #[serde(default = "default_compression_min_size")]
+    pub min_size: u16,
+    /// Accepts compressed request bodies. Otherwise, they are rejected with 415.
+    #[serde(default = "default_decompress_requests")]
+    pub decompress_requests: bool,
+    /// Overrides for some routes.
+    #[serde(default)]
+    pub routes: Vec<RouteCompression>,
+}
+
+fn default_compression_algorithms() -> Vec<CompressionAlgorithm> {
+    vec![
+        CompressionAlgorithm::Zstd,
+        CompressionAlgorithm::Br,
+        CompressionAlgorithm::Gzip,
+    ]
+}
+
+fn default_compression_min_size() -> u16 {
+    1024
+}
+
+fn default_decompress_requests() -> bool {
+    true
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CompressionAlgorithm {
+    Gzip,
+    Br,
+    Zstd,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RouteCompression {
+    /// Route as registered, e.g., `/api/v2/users/:id`.
+    pub path: String,
+    /// All methods if not set.
+    pub method: Option<String>,
+    /// Disables the response compression of the route if false.
+    pub enabled: Option<bool>,
+    pub min_size: Option<u16>,
+    pub decompress_requests: Option<bool>,
+}
+
+/// Responses stored for the `Idempotency-Key` header of the requests that
+/// create resources.
+#[derive(Debug, Deserialize)]
+pub struct Idempotency {
+    /// Seconds to replay the response to the retries. The key can be reused
+    /// for another request after that.
+    #[serde(default = "default_idempotency_ttl")]
+    pub ttl: u64,
+    /// Seconds between the deletions of the expired responses. 0 disables them.
+    #[serde(default = "default_idempotency_purge_interval")]
+    pub purge_interval: u64,
+}
+
+impl Default for Idempotency {
+    fn default() -> Self {
+        Self {
+            ttl: default_idempotency_ttl(),
+            purge_interval: default_idempotency_purge_interval(),
+        }
+    }
+}
+
+fn default_idempotency_ttl() -> u64 {
+    24 * 60 * 60
+}
+
+fn default_idempotency_purge_interval() -> u64 {
+    60 * 60
+}
+
+/// Listing of the users in pages.
+#[derive(Debug, Deserialize)]
+pub struct Pagination {
+    /// Users per page if the request does not ask for a size.
+    #[serde(default = "default_page_size")]
+    pub default_page_size: u32,
+    /// Users per page at most.
+    #[serde(default = "default_max_page_size")]
+    pub max_page_size: u32,
+    /// Signs the cursors, so that the clients cannot make them up. The instances
+    /// behind a load balancer have to share it. A random one is generated if not
+    /// set, which invalidates the cursors on restart.
+    pub cursor_secret: Option<Secret<String>>,
+}
+
+impl Default for Pagination {
+    fn default() -> Self {
+        Self {
+            default_page_size: default_page_size(),
+            max_page_size: default_max_page_size(),
+            cursor_secret: None,
+        }
+    }
+}
+
+fn default_page_size() -> u32 {
+    20
+}
+
+fn default_max_page_size() -> u32 {
+    100
+}
+
+/// Permanent deletion of the soft-deleted users.
+#[derive(Debug, Deserialize)]
+pub struct Retention {
+    /// Seconds to keep the deleted users for, during which they can be restored.
+    #[serde(default = "default_deleted_users_retention")]
+    pub deleted_users: u64,
+    /// Seconds between the purges of the users kept longer. 0 disables them.
+    #[serde(default = "default_retention_purge_interval")]
+    pub purge_interval: u64,
+}
+
+impl Default for Retention {
+    fn default() -> Self {
+        Self {
+            deleted_users: default_deleted_users_retention(),
+            purge_interval: default_retention_purge_interval(),
+        }
+    }
+}
+
+fn default_deleted_users_retention() -> u64 {
+    30 * 24 * 60 * 60
+}
+
+fn default_retention_purge_interval() -> u64 {
+    60 * 60
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Listener {
+    #[serde(default = "default_listener_address")]
+    pub address: IpAddr,
+    /// TCP port. Either this or `unix` has to be set.
+    pub port: Option<u16>,
+    pub unix: Option<UnixSocket>,
+    /// Serves plain HTTP if not set, e.g., behind a TLS-terminating proxy.
+    pub tls: Option<TLS>,
+    #[serde(default = "default_route_groups")]
+    pub routes: Vec<RouteGroup>,
+    /// Redirects every request to HTTPS on this port instead of serving the routes.
+    pub redirect_to: Option<u16>,
+}
+
+fn default_listener_address() -> IpAddr {
+    IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+}
+
+// The admin and metrics routes are for internal listeners only, so they have to
+// be enabled explicitly.
+fn default_route_groups() -> Vec<RouteGroup> {
+    vec![RouteGroup::Api]
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UnixSocket {
+    pub path: String,
+    /// Permissions of the socket file in octal, e.g., "660".
+    pub mode: Option<String>,
+    /// Removes the socket file left behind by a previous process, if any.
+    #[serde(default = "default_remove_stale")]
+    pub remove_stale: bool,
+}
+
+fn default_remove_stale() -> bool {
+    true
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RouteGroup {
+    /// The public API under `/api`.
+    Api,
+    /// Health checks for load balancers and orchestrators.
+    Admin,
+    /// Prometheus metrics.
+    Metrics,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TLS {
+    pub cert_file: String,
+    pub key_file: String,
+    /// Authenticates the clients with their certificates (mTLS) if set.
+    pub client_auth: Option<ClientAuth>,
+    /// Seconds between checks of the cert and key files for changes. The new
+    /// certificate is served without restarting if they have changed. 0 disables
+    /// the checks.
+    #[serde(default = "default_tls_reload_interval")]
+    pub reload_interval: u64,
+    /// Days before the expiry of the certificate to start warning about it.
+    #[serde(default = "default_tls_expiry_warning")]
+    pub expiry_warning: u64,
+    /// Additional certificates selected by the server name the client asks for
+    /// (SNI). `cert_file` is served if none of them matches.
+    #[serde(default)]
+    pub sni: Vec<SniCertificate>,
+    /// Protocol versions to accept.
+    #[serde(default = "default_tls_versions")]
+    pub versions: Vec<TlsVersion>,
+    /// Cipher suites in order of preference, e.g., `TLS13_AES_256_GCM_SHA384`.
+    /// The safe defaults of rustls are used if empty.
+    #[serde(default)]
+    pub cipher_suites: Vec<String>,
+    /// Application protocols to negotiate in order of preference.
+    #[serde(default = "default_tls_alpn")]
+    pub alpn: Vec<String>,
+}
+
+impl TLS {
+    fn new(cert_file: String, key_file: String) -> Self {
+        Self {
+            cert_file,
+            key_file,
+            client_auth: None,
+            reload_interval: default_tls_reload_interval(),
+            expiry_warning: default_tls_expiry_warning(),
+            sni: Vec::new(),
+            versions: default_tls_versions(),
+            cipher_suites: Vec::new(),
+            alpn: default_tls_alpn(),
+        }
+    }
+}
+
+fn default_tls_reload_interval() -> u64 {
+    60
+}
+
+fn default_tls_expiry_warning() -> u64 {
+    30
+}
+
+fn default_tls_versions() -> Vec<TlsVersion> {
+    vec![TlsVersion::V1_2, TlsVersion::V1_3]
+}
+
+fn default_tls_alpn() -> Vec<String> {
+    vec![String::from("h2"), String::from("http/1.1")]
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SniCertificate {
+    /// Host names to serve the certificate for. `*.example.com` matches any
+    /// single label in place of `*`.
+    pub server_names: Vec<String>,
+    pub cert_file: String,
+    pub key_file: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum TlsVersion {
+    #[serde(rename = "1.2")]
+    V1_2,
+    #[serde(rename = "1.3")]
+    V1_3,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ClientAuth {
+    /// CA certificates, in PEM, that have issued the client certificates.
+    pub ca_file: String,
+    #[serde(default)]
+    pub mode: ClientAuthMode,
+    /// Certificate revocation lists in PEM.
+    #[serde(default)]
+    pub crl_files: Vec<String>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ClientAuthMode {
+    /// Rejects the clients without a valid certificate.
+    #[default]
+    Required,
+    /// Accepts the clients without a certificate, but rejects invalid ones.
+    Optional,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Health {
+    /// Timeout of the database ping in milliseconds.
+    #[serde(default = "default_health_timeout")]
+    pub database_timeout: u64,
+    /// Timeout of the database schema check in milliseconds.
+    #[serde(default = "default_health_timeout")]
+    pub schema_timeout: u64,
+}
+
+impl Default for Health {
+    fn default() -> Self {
+        Self {
+            database_timeout: default_health_timeout(),
+            schema_timeout: default_health_timeout(),
+        }
+    }
+}
+
+fn default_health_timeout() -> u64 {
+    1000
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Telemetry {
+    /// OTLP/HTTP traces endpoint, e.g., `http://127.0.0.1:4318/v1/traces`.
+    pub endpoint: String,
+    #[serde(default = "default_service_name")]
+    pub service_name: String,
+    #[serde(default = "default_sampling_ratio")]
+    pub sampling_ratio: f64,
+    /// Export timeout in seconds.
+    #[serde(default = "default_telemetry_timeout")]
+    pub timeout: u64,
+}
+
+fn default_service_name() -> String {
+    String::from(env!("CARGO_PKG_NAME"))
+}
+
+fn default_sampling_ratio() -> f64 {
+    1.0
+}
+
+fn default_telemetry_timeout() -> u64 {
+    10
 }
 
 pub fn load() -> Result<Configuration> {
@@ -46,7 +645,9 @@ pub fn load() -> Result<Configuration> {
     file.read_to_string(&mut contents).context(format!(
         "failed to load the config file: {DEFAULT_CONFIG_FILE_PATH}"
     ))?;
-    Ok(serde_yaml::from_str(&contents)?)
+    let mut config: Configuration = serde_yaml::from_str(&contents)?;
+    config.http.migrate_legacy_listener()?;
+    Ok(config)
 }
 
 struct LogLevelVisitor;
@@ -1,2 +1,4 @@
 pub mod controller;
 pub mod entity;
+pub mod search;
+pub mod secret;
@@ -1,13 +1,29 @@
 use crate::core::entity::CreateUserParams as EntityCreateUserParams;
+use crate::core::entity::DeleteUserParams as EntityDeleteUserParams;
+use crate::core::entity::GetStoredResponseParams as EntityGetStoredResponseParams;
 use crate::core::entity::GetUserParams as EntityGetUserParams;
-use crate::core::entity::{DatabaseTransaction, User};
+use crate::core::entity::ListUsersParams as EntityListUsersParams;
+use crate::core::entity::PurgeDeletedUsersParams as EntityPurgeDeletedUsersParams;
+use crate::core::entity::PutStoredResponseParams as EntityPutStoredResponseParams;
+use crate::core::entity::RestoreUserParams as EntityRestoreUserParams;
+use crate::core::entity::SearchUsersParams as EntitySearchUsersParams;
+use crate::core::entity::UpdateUserParams as EntityUpdateUserParams;
+use crate::core::entity::{
+    DatabaseTransaction, SearchHit, StoredResponse, User, UserFilter, UserKey, UserSort,
+};
+use crate::core::search;
+use crate::core::secret::Secret;
+use crate::metrics::METRICS;
 
 use std::fmt::Debug;
-use std::sync::mpsc;
+use std::sync::{mpsc, Arc};
 use std::time::Duration;
 
 use anyhow::{Context, Result};
 use futures::future::BoxFuture;
+use scopeguard::ScopeGuard;
+use tracing::field::Empty;
+use tracing::Instrument;
 
 pub struct Controller<T> {
     db: T,
@@ -24,18 +40,18 @@ where
 #[derive(Debug, Clone)]
 pub struct CreateUserParams {
     pub username: String,
-    pub password: String,
+    pub password: Secret<String>,
     pub age: u16,
     pub address: String,
 }
 
-impl Into<EntityCreateUserParams> for CreateUserParams {
-    fn into(self) -> EntityCreateUserParams {
-        EntityCreateUserParams {
-            username: self.username,
-            password: self.password,
-            age: self.age,
-            address: self.address,
+impl From<CreateUserParams> for EntityCreateUserParams {
+    fn from(params: CreateUserParams) -> Self {
+        Self {
+            username: params.username,
+            password: params.password,
+            age: params.age,
+            address: params.address,
         }
     }
 }
@@ -43,47 +59,210 @@ impl Into<EntityCreateUserParams> for CreateUserParams {
 #[derive(Debug, Clone)]
 pub struct GetUserParams {
     pub id: u64,
+    pub include_deleted: bool,
 }
 
-impl Into<EntityGetUserParams> for GetUserParams {
-    fn into(self) -> EntityGetUserParams {
-        EntityGetUserParams { id: self.id }
+impl From<GetUserParams> for EntityGetUserParams {
+    fn from(params: GetUserParams) -> Self {
+        Self {
+            id: params.id,
+            include_deleted: params.include_deleted,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct UpdateUserParams {
+    pub id: u64,
+    pub username: Option<String>,
+    pub password: Option<Secret<String>>,
+    pub age: Option<u16>,
+    pub address: Option<String>,
+    pub expected_version: Option<u64>,
+}
+
+impl From<UpdateUserParams> for EntityUpdateUserParams {
+    fn from(params: UpdateUserParams) -> Self {
+        Self {
+            id: params.id,
+            username: params.username,
+            password: params.password,
+            age: params.age,
+            address: params.address,
+            expected_version: params.expected_version,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DeleteUserParams {
+    pub id: u64,
+    pub expected_version: Option<u64>,
+}
+
+impl From<DeleteUserParams> for EntityDeleteUserParams {
+    fn from(params: DeleteUserParams) -> Self {
+        Self {
+            id: params.id,
+            expected_version: params.expected_version,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RestoreUserParams {
+    pub id: u64,
+    pub expected_version: Option<u64>,
+}
+
+impl From<RestoreUserParams> for EntityRestoreUserParams {
+    fn from(params: RestoreUserParams) -> Self {
+        Self {
+            id: params.id,
+            expected_version: params.expected_version,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PurgeDeletedUsersParams {
+    pub retention: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct ListUsersParams {
+    pub filter: UserFilter,
+    pub sort: UserSort,
+    pub after: Option<UserKey>,
+    pub limit: u32,
+}
+
+impl From<ListUsersParams> for EntityListUsersParams {
+    fn from(params: ListUsersParams) -> Self {
+        Self {
+            filter: params.filter,
+            sort: params.sort,
+            after: params.after,
+            limit: params.limit,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SearchUsersParams {
+    pub query: String,
+    pub limit: u32,
+}
+
+impl From<SearchUsersParams> for EntitySearchUsersParams {
+    fn from(params: SearchUsersParams) -> Self {
+        let mut terms = search::tokenize(&params.query);
+        terms.sort();
+        terms.dedup();
+        Self {
+            terms,
+            limit: params.limit,
+        }
     }
 }
 
+/// Identifies the retries of a request, so that its write is applied only once.
+#[derive(Debug, Clone)]
+pub struct IdempotencyKey {
+    pub key: String,
+    /// Digest of the request, which has to match the one of the stored response.
+    pub fingerprint: String,
+    /// Seconds to keep the response.
+    pub ttl: u64,
+}
+
+#[derive(Debug)]
+pub enum Idempotent {
+    /// The first request with the key. Its response has been stored.
+    Stored(StoredResponse),
+    /// A retry, which gets the stored response.
+    Replayed(StoredResponse),
+    /// The key has been used by another request.
+    Mismatched,
+}
+
 const MAX_DEADLOCK_RETRY: usize = 5;
+// Users purged per transaction, so that none holds the locks for long.
+const PURGE_BATCH_SIZE: u32 = 1000;
+
+type Callback<T> = Box<dyn for<'a> FnMut(u64, &'a T) -> BoxFuture<'a, Result<()>> + Send>;
 
 impl<T: DatabaseTransaction + Send + Sync> Controller<T> {
     pub fn new(db: T) -> Self {
         Self { db }
     }
 
-    async fn invoke(
-        &self,
-        mut callback: Box<dyn for<'a> FnMut(u64, &'a T) -> BoxFuture<'a, Result<()>> + Send>,
-    ) -> Result<()> {
+    pub async fn ping(&self) -> Result<()> {
+        self.db.ping().await
+    }
+
+    pub async fn check_schema(&self) -> Result<()> {
+        self.db.check_schema().await
+    }
+
+    pub async fn close(&self) -> Result<()> {
+        self.db.close().await
+    }
+
+    async fn invoke(&self, mut callback: Callback<T>) -> Result<()> {
         let mut deadlock_count: usize = 0;
 
         loop {
-            let tx_id = self.db.begin().await?;
+            let span =
+                tracing::info_span!("transaction", attempt = deadlock_count + 1, tx_id = Empty);
+            let tx_id = self.db.begin().instrument(span.clone()).await?;
+            span.record("tx_id", tx_id);
+            METRICS
+                .database_transactions
+                .with_label_values(&["begin"])
+                .inc();
+            // Rolls back the transaction if this future is dropped before it ends,
+            // e.g., when the request has timed out.
+            let abandon = scopeguard::guard(tx_id, |tx_id| {
+                log::warn!("abandoning a database transaction: tx_id = {tx_id}");
+                self.db.abandon(tx_id);
+                METRICS
+                    .database_transactions
+                    .with_label_values(&["abandon"])
+                    .inc();
+            });
 
-            match callback(tx_id, &self.db).await {
+            let fut = span.in_scope(|| callback(tx_id, &self.db));
+            match fut.instrument(span.clone()).await {
                 Ok(_) => {
+                    ScopeGuard::into_inner(abandon);
                     self.db
                         .commit(tx_id)
+                        .instrument(span)
                         .await
                         .context("failed to commit a database transaction")?;
+                    METRICS
+                        .database_transactions
+                        .with_label_values(&["commit"])
+                        .inc();
                     return Ok(());
                 }
                 Err(err) => {
-                    let deadlock = self.db.is_deadlock(tx_id).await?;
+                    let deadlock = self.db.is_deadlock(tx_id).instrument(span.clone()).await?;
+                    ScopeGuard::into_inner(abandon);
                     self.db
                         .rollback(tx_id)
+                        .instrument(span)
                         .await
                         .context("failed to rollback a database transaction")?;
+                    METRICS
+                        .database_transactions
+                        .with_label_values(&["rollback"])
+                        .inc();
 
                     if deadlock && deadlock_count < MAX_DEADLOCK_RETRY {
                         deadlock_count += 1;
+                        METRICS.deadlock_retries.inc();
                         tokio::time::sleep(Duration::from_millis(500)).await;
                         continue;
                     }
@@ -100,7 +279,9 @@ impl<T: DatabaseTransaction + Send + Sync> Controller<T> {
     {
         let params = params.into();
         let (tx_chan, rx_chan) = mpsc::channel();
+        let span = tracing::info_span!("create_user", user_id = Empty);
 
+        let callback_span = span.clone();
         let callback = constrain_callback(move |tx_id: u64, tx: &T| {
             log::debug!("callback invoked");
             // We need these clones because this callback is FnMut, which can be called
@@ -108,14 +289,16 @@ impl<T: DatabaseTransaction + Send + Sync> Controller<T> {
             // work.
             let params = params.clone();
             let tx_chan = tx_chan.clone();
+            let span = callback_span.clone();
             let fut = async move {
                 let user = tx.create_user(tx_id, params).await?;
+                span.record("user_id", user.id);
                 tx_chan.send(user)?;
                 Ok(())
             };
             Box::pin(fut) as BoxFuture<'_, Result<()>>
         });
-        self.invoke(Box::new(callback)).await?;
+        self.invoke(Box::new(callback)).instrument(span).await?;
 
         Ok(rx_chan.recv()?)
     }
@@ -126,6 +309,7 @@ impl<T: DatabaseTransaction + Send + Sync> Controller<T> {
     {
         let params = params.into();
         let (tx_chan, rx_chan) = mpsc::channel();
+        let span = tracing::info_span!("get_user", user_id = params.id);
 
         let callback = constrain_callback(move |tx_id: u64, tx: &T| {
             log::debug!("callback invoked");
@@ -141,6 +325,265 @@ impl<T: DatabaseTransaction + Send + Sync> Controller<T> {
             };
             Box::pin(fut) as BoxFuture<'_, Result<()>>
         });
+        self.invoke(Box::new(callback)).instrument(span).await?;
+
+        Ok(rx_chan.recv()?)
+    }
+
+    pub async fn update_user<U>(&self, params: U) -> Result<Option<User>>
+    where
+        U: Into<UpdateUserParams>,
+    {
+        let params = params.into();
+        let (tx_chan, rx_chan) = mpsc::channel();
+        let span = tracing::info_span!("update_user", user_id = params.id);
+
+        let callback = constrain_callback(move |tx_id: u64, tx: &T| {
+            log::debug!("callback invoked");
+            // We need these clones because this callback is FnMut, which can be called
+            // multiple times. Otherwise, only the very first call for this callback will
+            // work.
+            let params = params.clone();
+            let tx_chan = tx_chan.clone();
+            let fut = async move {
+                let user = tx.update_user(tx_id, params).await?;
+                tx_chan.send(user)?;
+                Ok(())
+            };
+            Box::pin(fut) as BoxFuture<'_, Result<()>>
+        });
+        self.invoke(Box::new(callback)).instrument(span).await?;
+
+        Ok(rx_chan.recv()?)
+    }
+
+    pub async fn delete_user<U>(&self, params: U) -> Result<bool>
+    where
+        U: Into<DeleteUserParams>,
+    {
+        let params = params.into();
+        let (tx_chan, rx_chan) = mpsc::channel();
+        let span = tracing::info_span!("delete_user", user_id = params.id);
+
+        let callback = constrain_callback(move |tx_id: u64, tx: &T| {
+            log::debug!("callback invoked");
+            // We need these clones because this callback is FnMut, which can be called
+            // multiple times. Otherwise, only the very first call for this callback will
+            // work.
+            let params = params.clone();
+            let tx_chan = tx_chan.clone();
+            let fut = async move {
+                let deleted = tx.delete_user(tx_id, params).await?;
+                tx_chan.send(deleted)?;
+                Ok(())
+            };
+            Box::pin(fut) as BoxFuture<'_, Result<()>>
+        });
+        self.invoke(Box::new(callback)).instrument(span).await?;
+
+        Ok(rx_chan.recv()?)
+    }
+
+    pub async fn restore_user<U>(&self, params: U) -> Result<Option<User>>
+    where
+        U: Into<RestoreUserParams>,
+    {
+        let params = params.into();
+        let (tx_chan, rx_chan) = mpsc::channel();
+        let span = tracing::info_span!("restore_user", user_id = params.id);
+
+        let callback = constrain_callback(move |tx_id: u64, tx: &T| {
+            log::debug!("callback invoked");
+            // We need these clones because this callback is FnMut, which can be called
+            // multiple times. Otherwise, only the very first call for this callback will
+            // work.
+            let params = params.clone();
+            let tx_chan = tx_chan.clone();
+            let fut = async move {
+                let user = tx.restore_user(tx_id, params).await?;
+                tx_chan.send(user)?;
+                Ok(())
+            };
+            Box::pin(fut) as BoxFuture<'_, Result<()>>
+        });
+        self.invoke(Box::new(callback)).instrument(span).await?;
+
+        Ok(rx_chan.recv()?)
+    }
+
+    pub async fn list_users<U>(&self, params: U) -> Result<Vec<User>>
+    where
+        U: Into<ListUsersParams>,
+    {
+        let params = params.into();
+        let (tx_chan, rx_chan) = mpsc::channel();
+        let span = tracing::info_span!("list_users");
+
+        let callback = constrain_callback(move |tx_id: u64, tx: &T| {
+            log::debug!("callback invoked");
+            // We need these clones because this callback is FnMut, which can be called
+            // multiple times. Otherwise, only the very first call for this callback will
+            // work.
+            let params = params.clone();
+            let tx_chan = tx_chan.clone();
+            let fut = async move {
+                let users = tx.list_users(tx_id, params).await?;
+                tx_chan.send(users)?;
+                Ok(())
+            };
+            Box::pin(fut) as BoxFuture<'_, Result<()>>
+        });
+        self.invoke(Box::new(callback)).instrument(span).await?;
+
+        Ok(rx_chan.recv()?)
+    }
+
+    pub async fn search_users<U>(&self, params: U) -> Result<Vec<SearchHit>>
+    where
+        U: Into<SearchUsersParams>,
+    {
+        let params = params.into();
+        let (tx_chan, rx_chan) = mpsc::channel();
+        let span = tracing::info_span!("search_users");
+
+        let callback = constrain_callback(move |tx_id: u64, tx: &T| {
+            log::debug!("callback invoked");
+            // We need these clones because this callback is FnMut, which can be called
+            // multiple times. Otherwise, only the very first call for this callback will
+            // work.
+            let params = params.clone();
+            let tx_chan = tx_chan.clone();
+            let fut = async move {
+                let hits = tx.search_users(tx_id, params).await?;
+                tx_chan.send(hits)?;
+                Ok(())
+            };
+            Box::pin(fut) as BoxFuture<'_, Result<()>>
+        });
+        self.invoke(Box::new(callback)).instrument(span).await?;
+
+        Ok(rx_chan.recv()?)
+    }
+
+    /// Creates a user unless a request with the same idempotency key has done it.
+    /// `respond` makes the response to store, which is committed along with the
+    /// user.
+    pub async fn create_user_once<U, F>(
+        &self,
+        params: U,
+        key: IdempotencyKey,
+        respond: F,
+    ) -> Result<Idempotent>
+    where
+        U: Into<CreateUserParams>,
+        F: Fn(&User) -> Result<StoredResponse> + Send + Sync + 'static,
+    {
+        let params = params.into();
+        let respond = Arc::new(respond);
+        let (tx_chan, rx_chan) = mpsc::channel();
+        let span = tracing::info_span!("create_user_once", user_id = Empty, replayed = Empty);
+
+        let callback_span = span.clone();
+        let callback = constrain_callback(move |tx_id: u64, tx: &T| {
+            log::debug!("callback invoked");
+            // We need these clones because this callback is FnMut, which can be called
+            // multiple times. Otherwise, only the very first call for this callback will
+            // work.
+            let params = params.clone();
+            let key = key.clone();
+            let respond = respond.clone();
+            let tx_chan = tx_chan.clone();
+            let span = callback_span.clone();
+            let fut = async move {
+                let get_params = EntityGetStoredResponseParams {
+                    key: key.key.clone(),
+                };
+                let result = match tx.get_stored_response(tx_id, get_params).await? {
+                    Some(v) if v.fingerprint == key.fingerprint => Idempotent::Replayed(v),
+                    Some(_) => Idempotent::Mismatched,
+                    None => {
+                        let user = tx.create_user(tx_id, params).await?;
+                        span.record("user_id", user.id);
+                        let response = respond(&user)?;
+                        let put_params = EntityPutStoredResponseParams {
+                            key: key.key,
+                            response: response.clone(),
+                            ttl: key.ttl,
+                            user_id: Some(user.id),
+                        };
+                        tx.put_stored_response(tx_id, put_params).await?;
+                        Idempotent::Stored(response)
+                    }
+                };
+                span.record("replayed", matches!(result, Idempotent::Replayed(_)));
+                tx_chan.send(result)?;
+                Ok(())
+            };
+            Box::pin(fut) as BoxFuture<'_, Result<()>>
+        });
+        self.invoke(Box::new(callback)).instrument(span).await?;
+
+        Ok(rx_chan.recv()?)
+    }
+
+    /// Deletes the responses stored for the expired idempotency keys.
+    pub async fn purge_stored_responses(&self) -> Result<u64> {
+        let (tx_chan, rx_chan) = mpsc::channel();
+        let span = tracing::info_span!("purge_stored_responses");
+
+        let callback = constrain_callback(move |tx_id: u64, tx: &T| {
+            log::debug!("callback invoked");
+            let tx_chan = tx_chan.clone();
+            let fut = async move {
+                let purged = tx.purge_stored_responses(tx_id).await?;
+                tx_chan.send(purged)?;
+                Ok(())
+            };
+            Box::pin(fut) as BoxFuture<'_, Result<()>>
+        });
+        self.invoke(Box::new(callback)).instrument(span).await?;
+
+        Ok(rx_chan.recv()?)
+    }
+
+    /// Permanently deletes the users soft-deleted longer than the retention
+    /// period, a batch per transaction, and returns how many have been deleted.
+    pub async fn purge_deleted_users<U>(&self, params: U) -> Result<u64>
+    where
+        U: Into<PurgeDeletedUsersParams>,
+    {
+        let params = params.into();
+        let span = tracing::info_span!("purge_deleted_users", purged = Empty);
+        let mut purged = 0;
+        loop {
+            let count = self
+                .purge_deleted_users_batch(params.retention)
+                .instrument(span.clone())
+                .await?;
+            purged += count;
+            if count < PURGE_BATCH_SIZE as u64 {
+                span.record("purged", purged);
+                return Ok(purged);
+            }
+        }
+    }
+
+    async fn purge_deleted_users_batch(&self, retention: u64) -> Result<u64> {
+        let (tx_chan, rx_chan) = mpsc::channel();
+        let callback = constrain_callback(move |tx_id: u64, tx: &T| {
+            log::debug!("callback invoked");
+            let tx_chan = tx_chan.clone();
+            let fut = async move {
+                let params = EntityPurgeDeletedUsersParams {
+                    retention,
+                    limit: PURGE_BATCH_SIZE,
+                };
+                let purged = tx.purge_deleted_users(tx_id, params).await?;
+                tx_chan.send(purged)?;
+                Ok(())
+            };
+            Box::pin(fut) as BoxFuture<'_, Result<()>>
+        });
         self.invoke(Box::new(callback)).await?;
 
         Ok(rx_chan.recv()?)
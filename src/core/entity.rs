@@ -1,27 +1,86 @@
-use std::fmt::Debug;
+use crate::core::secret::Secret;
+
+use std::cmp::Ordering;
+use std::fmt::{self, Debug, Display};
+use std::ops::Range;
 
 use anyhow::Result;
 use async_trait::async_trait;
-use serde::Serialize;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
 
 #[async_trait]
 pub trait DatabaseTransaction: Debug {
+    // Checks that the database is reachable, outside of any transaction.
+    async fn ping(&self) -> Result<()>;
+    // Checks that the database schema has all the tables and columns we need.
+    async fn check_schema(&self) -> Result<()>;
+    // Rolls back all the open transactions and disconnects from the database.
+    async fn close(&self) -> Result<()>;
     async fn begin(&self) -> Result<u64>;
     async fn commit(&self, tx_id: u64) -> Result<()>;
     async fn rollback(&self, tx_id: u64) -> Result<()>;
     async fn is_deadlock(&self, tx_id: u64) -> Result<bool>;
+    // Gives up a transaction whose caller has gone away without committing or
+    // rolling it back. It must not block, as it is called on drop.
+    fn abandon(&self, tx_id: u64);
     async fn create_user<T>(&self, tx_id: u64, params: T) -> Result<User>
     where
         T: Into<CreateUserParams> + Send;
     async fn get_user<T>(&self, tx_id: u64, params: T) -> Result<Option<User>>
     where
         T: Into<GetUserParams> + Send;
+    // Returns None if the user does not exist or is deleted, and a `Conflict`
+    // error if its version is not the expected one.
+    async fn update_user<T>(&self, tx_id: u64, params: T) -> Result<Option<User>>
+    where
+        T: Into<UpdateUserParams> + Send;
+    // Soft-deletes the user by setting `deleted_at`. Returns false if the user
+    // does not exist or is deleted already, and a `Conflict` error if its version
+    // is not the expected one.
+    async fn delete_user<T>(&self, tx_id: u64, params: T) -> Result<bool>
+    where
+        T: Into<DeleteUserParams> + Send;
+    // Undoes the soft deletion of the user. A user that is not deleted is
+    // returned as is. Returns None if the user does not exist, and a `Conflict`
+    // error if its version is not the expected one.
+    async fn restore_user<T>(&self, tx_id: u64, params: T) -> Result<Option<User>>
+    where
+        T: Into<RestoreUserParams> + Send;
+    // Permanently deletes up to `limit` of the users soft-deleted longer than
+    // the retention period, along with the responses stored for their creation,
+    // and returns how many users have been deleted.
+    async fn purge_deleted_users<T>(&self, tx_id: u64, params: T) -> Result<u64>
+    where
+        T: Into<PurgeDeletedUsersParams> + Send;
+    // Returns the users meeting the filter, in the sort order.
+    async fn list_users<T>(&self, tx_id: u64, params: T) -> Result<Vec<User>>
+    where
+        T: Into<ListUsersParams> + Send;
+    // Returns the users with a word starting with each of the terms in their
+    // username or address, the most relevant first. Deleted users are excluded.
+    async fn search_users<T>(&self, tx_id: u64, params: T) -> Result<Vec<SearchHit>>
+    where
+        T: Into<SearchUsersParams> + Send;
+    // Returns the response stored for the idempotency key unless it has expired.
+    // The key is locked until the end of the transaction, so that the requests
+    // with the same key are served one at a time.
+    async fn get_stored_response<T>(&self, tx_id: u64, params: T) -> Result<Option<StoredResponse>>
+    where
+        T: Into<GetStoredResponseParams> + Send;
+    // Stores the response for the idempotency key, replacing an expired one.
+    async fn put_stored_response<T>(&self, tx_id: u64, params: T) -> Result<()>
+    where
+        T: Into<PutStoredResponseParams> + Send;
+    // Deletes the expired responses and returns how many have been deleted.
+    async fn purge_stored_responses(&self, tx_id: u64) -> Result<u64>;
 }
 
 #[derive(Debug)]
 pub struct CreateUserParams {
     pub username: String,
-    pub password: String,
+    pub password: Secret<String>,
     pub age: u16,
     pub address: String,
 }
@@ -29,13 +88,214 @@ pub struct CreateUserParams {
 #[derive(Debug)]
 pub struct GetUserParams {
     pub id: u64,
+    /// Returns the user even if it is soft-deleted.
+    pub include_deleted: bool,
+}
+
+/// Fields set to None are left unchanged.
+#[derive(Debug)]
+pub struct UpdateUserParams {
+    pub id: u64,
+    pub username: Option<String>,
+    pub password: Option<Secret<String>>,
+    pub age: Option<u16>,
+    pub address: Option<String>,
+    /// Updates the user only if it still has this version.
+    pub expected_version: Option<u64>,
+}
+
+#[derive(Debug)]
+pub struct DeleteUserParams {
+    pub id: u64,
+    /// Deletes the user only if it still has this version.
+    pub expected_version: Option<u64>,
+}
+
+#[derive(Debug)]
+pub struct RestoreUserParams {
+    pub id: u64,
+    /// Restores the user only if it still has this version.
+    pub expected_version: Option<u64>,
+}
+
+#[derive(Debug)]
+pub struct PurgeDeletedUsersParams {
+    /// Seconds to keep the soft-deleted users for.
+    pub retention: u64,
+    pub limit: u32,
+}
+
+#[derive(Debug)]
+pub struct ListUsersParams {
+    pub filter: UserFilter,
+    pub sort: UserSort,
+    /// Lists the users after this position in the sort order.
+    pub after: Option<UserKey>,
+    pub limit: u32,
+}
+
+/// Conditions that all the listed users meet. The ones set to None match any user.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UserFilter {
+    pub username_prefix: Option<String>,
+    pub min_age: Option<u16>,
+    pub max_age: Option<u16>,
+    pub address_contains: Option<String>,
+    /// Lists the soft-deleted users too.
+    #[serde(default)]
+    pub include_deleted: bool,
+}
+
+impl UserFilter {
+    // Ignores the case like LIKE does under the collation of the MySQL table.
+    pub fn matches(&self, user: &User) -> bool {
+        (self.include_deleted || user.deleted_at.is_none())
+            && self
+                .username_prefix
+                .as_ref()
+                .is_none_or(|v| user.username.to_lowercase().starts_with(&v.to_lowercase()))
+            && self.min_age.is_none_or(|v| user.age >= v)
+            && self.max_age.is_none_or(|v| user.age <= v)
+            && self
+                .address_contains
+                .as_ref()
+                .is_none_or(|v| user.address.to_lowercase().contains(&v.to_lowercase()))
+    }
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum UserSortField {
+    #[default]
+    Id,
+    Username,
+    Age,
+}
+
+/// Order of the listed users. The ties are broken by the id in the same
+/// direction, so that every user has a fixed position to continue from.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UserSort {
+    pub field: UserSortField,
+    pub descending: bool,
+}
+
+impl UserSort {
+    pub fn key(&self, user: &User) -> UserKey {
+        match self.field {
+            UserSortField::Id => UserKey::Id(user.id),
+            UserSortField::Username => UserKey::Username(user.username.clone(), user.id),
+            UserSortField::Age => UserKey::Age(user.age, user.id),
+        }
+    }
+
+    // Ignores the case of the usernames like the collation of the MySQL table,
+    // so that the users differing only in it are ordered by the id.
+    pub fn compare(&self, a: &UserKey, b: &UserKey) -> Ordering {
+        let ordering = match (a, b) {
+            (UserKey::Username(a, a_id), UserKey::Username(b, b_id)) => {
+                a.to_lowercase().cmp(&b.to_lowercase()).then(a_id.cmp(b_id))
+            }
+            _ => a.cmp(b),
+        };
+        if self.descending {
+            ordering.reverse()
+        } else {
+            ordering
+        }
+    }
+}
+
+/// Position of a user in a sort order, i.e., the sorted field and the id.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum UserKey {
+    Id(u64),
+    Username(String, u64),
+    Age(u16, u64),
+}
+
+#[derive(Debug)]
+pub struct SearchUsersParams {
+    /// Lowercase words of letters and digits, see `search::tokenize`.
+    pub terms: Vec<String>,
+    pub limit: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct SearchHit {
+    pub user: User,
+    /// Relevance to the terms, comparable only within the same search.
+    pub score: f64,
+    pub highlights: Vec<Highlight>,
+}
+
+/// Byte ranges of the matched words in a field of the user.
+#[derive(Debug, Clone)]
+pub struct Highlight {
+    pub field: &'static str,
+    pub ranges: Vec<Range<usize>>,
+}
+
+#[derive(Debug)]
+pub struct GetStoredResponseParams {
+    pub key: String,
+}
+
+#[derive(Debug)]
+pub struct PutStoredResponseParams {
+    pub key: String,
+    pub response: StoredResponse,
+    /// Seconds to keep the response.
+    pub ttl: u64,
+    /// User created by the request. Purging the user deletes the response too.
+    pub user_id: Option<u64>,
+}
+
+/// Response to the first request with an idempotency key, replayed to its retries.
+#[derive(Debug, Clone)]
+pub struct StoredResponse {
+    /// Digest of the request, which tells a retry from another request
+    /// reusing the key.
+    pub fingerprint: String,
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+/// A user as returned by the v1 API. Breaking change: the responses no longer
+/// include the password, which they used to echo back in plain text.
+#[derive(Debug, Clone, Serialize, ToSchema)]
 pub struct User {
     pub id: u64,
     pub username: String,
-    pub password: String,
+    // Never leaves the process, not even in the v1 responses.
+    #[serde(skip_serializing)]
+    #[schema(ignore)]
+    pub password: Secret<String>,
     pub age: u16,
     pub address: String,
+    /// Incremented on every update, starting from 1.
+    pub version: u64,
+    /// Set while the user is soft-deleted.
+    #[schema(value_type = Option<String>, format = DateTime)]
+    pub deleted_at: Option<DateTime<Utc>>,
 }
+
+/// The user has been changed since the caller read it.
+#[derive(Debug)]
+pub struct Conflict {
+    pub id: u64,
+    pub expected_version: u64,
+    pub actual_version: u64,
+}
+
+impl Display for Conflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "version conflict: id = {}, expected = {}, actual = {}",
+            self.id, self.expected_version, self.actual_version
+        )
+    }
+}
+
+impl std::error::Error for Conflict {}
@@ -0,0 +1,127 @@
+use crate::core::entity::{Highlight, User};
+
+use std::ops::Range;
+
+/// Splits a text into lowercase words of letters and digits, which is how the
+/// search backends index the users and parse the queries.
+pub fn tokenize(text: &str) -> Vec<String> {
+    words(text)
+        .into_iter()
+        .map(|(_, v)| v.to_lowercase())
+        .collect()
+}
+
+/// Fields of the user which contain a word starting with one of the terms, with
+/// the byte ranges of the matched prefixes.
+pub fn highlights(user: &User, terms: &[String]) -> Vec<Highlight> {
+    [("username", &user.username), ("address", &user.address)]
+        .into_iter()
+        .filter_map(|(field, text)| {
+            let ranges = matches(text, terms);
+            (!ranges.is_empty()).then_some(Highlight { field, ranges })
+        })
+        .collect()
+}
+
+fn matches(text: &str, terms: &[String]) -> Vec<Range<usize>> {
+    words(text)
+        .into_iter()
+        .filter_map(|(start, word)| {
+            let lowercase = word.to_lowercase();
+            // The longest one, if a term is a prefix of another.
+            let term = terms
+                .iter()
+                .filter(|v| lowercase.starts_with(v.as_str()))
+                .max_by_key(|v| v.len())?;
+            // Counted in characters, as lowercasing may change their lengths.
+            let length = word
+                .char_indices()
+                .nth(term.chars().count())
+                .map_or(word.len(), |(i, _)| i);
+            Some(start..start + length)
+        })
+        .collect()
+}
+
+// Words with their byte offsets.
+fn words(text: &str) -> Vec<(usize, &str)> {
+    let mut result = Vec::new();
+    let mut start = None;
+    for (i, c) in text.char_indices() {
+        match (c.is_alphanumeric(), start) {
+            (true, None) => start = Some(i),
+            (false, Some(v)) => {
+                result.push((v, &text[v..i]));
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    if let Some(v) = start {
+        result.push((v, &text[v..]));
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(username: &str, address: &str) -> User {
+        User {
+            id: 1,
+            username: username.to_string(),
+            password: String::new().into(),
+            age: 30,
+            address: address.to_string(),
+            version: 1,
+            deleted_at: None,
+        }
+    }
+
+    fn terms(terms: &[&str]) -> Vec<String> {
+        terms.iter().map(|v| v.to_string()).collect()
+    }
+
+    #[test]
+    fn tokenize_splits_on_non_alphanumerics() {
+        assert_eq!(
+            tokenize("  O'Brien, 12-B Main St.  "),
+            ["o", "brien", "12", "b", "main", "st"]
+        );
+        assert_eq!(tokenize("Zürich ÄBC"), ["zürich", "äbc"]);
+        assert!(tokenize(" - !? ").is_empty());
+    }
+
+    #[test]
+    fn highlights_matched_prefixes() {
+        let user = user("alice", "12 Main Street, Mainz");
+        let highlights = highlights(&user, &terms(&["main"]));
+        assert_eq!(highlights.len(), 1);
+        assert_eq!(highlights[0].field, "address");
+        assert_eq!(highlights[0].ranges, [3..7, 16..20]);
+    }
+
+    #[test]
+    fn highlights_longest_term() {
+        let user = user("Alexander", "");
+        let highlights = highlights(&user, &terms(&["al", "alex"]));
+        assert_eq!(highlights[0].field, "username");
+        assert_eq!(highlights[0].ranges, vec![0..4]);
+    }
+
+    #[test]
+    fn highlights_count_characters() {
+        // Ä takes two bytes.
+        let user = user("ÄBC", "");
+        assert_eq!(highlights(&user, &terms(&["äb"]))[0].ranges, vec![0..3]);
+    }
+
+    #[test]
+    fn highlights_nothing_without_match() {
+        let user = user("alice", "Main Street");
+        assert!(highlights(&user, &terms(&["bob"])).is_empty());
+        // Only the starts of the words match.
+        assert!(highlights(&user, &terms(&["lice"])).is_empty());
+    }
+}
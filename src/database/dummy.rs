@@ -1,13 +1,84 @@
-use crate::core::entity::{CreateUserParams, DatabaseTransaction, GetUserParams, User};
+use crate::core::entity::{
+    Conflict, CreateUserParams, DatabaseTransaction, DeleteUserParams, GetStoredResponseParams,
+    GetUserParams, ListUsersParams, PurgeDeletedUsersParams, PutStoredResponseParams,
+    RestoreUserParams, SearchHit, SearchUsersParams, StoredResponse, UpdateUserParams, User,
+};
+use crate::core::search;
 
-use anyhow::Result;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
 use async_trait::async_trait;
+use chrono::Utc;
+
+/// Keeps the data in memory, e.g., to run the server without MySQL. The
+/// transactions are not isolated, and the writes of a rolled back one remain.
+#[derive(Debug, Default)]
+pub struct Dummy {
+    data: Mutex<Data>,
+}
+
+#[derive(Debug, Default)]
+struct Data {
+    users: BTreeMap<u64, User>,
+    last_id: u64,
+    // With the expiry and the created user.
+    responses: HashMap<String, (StoredResponse, Instant, Option<u64>)>,
+    // Number of occurrences of each word in the users.
+    index: BTreeMap<String, HashMap<u64, u32>>,
+}
+
+impl Data {
+    // The user unless it is soft-deleted.
+    fn live_user(&self, id: u64) -> Option<&User> {
+        self.users.get(&id).filter(|v| v.deleted_at.is_none())
+    }
 
-#[derive(Debug)]
-pub struct Dummy;
+    fn index(&mut self, user: &User) {
+        for word in words(user) {
+            *self
+                .index
+                .entry(word)
+                .or_default()
+                .entry(user.id)
+                .or_default() += 1;
+        }
+    }
+
+    fn unindex(&mut self, user: &User) {
+        for word in words(user) {
+            if let Some(v) = self.index.get_mut(&word) {
+                v.remove(&user.id);
+                if v.is_empty() {
+                    self.index.remove(&word);
+                }
+            }
+        }
+    }
+}
+
+fn words(user: &User) -> Vec<String> {
+    let mut words = search::tokenize(&user.username);
+    words.extend(search::tokenize(&user.address));
+    words
+}
 
 #[async_trait]
 impl DatabaseTransaction for Dummy {
+    async fn ping(&self) -> Result<()> {
+        Ok(())
+    }
+
+    async fn check_schema(&self) -> Result<()> {
+        Ok(())
+    }
+
+    async fn close(&self) -> Result<()> {
+        Ok(())
+    }
+
     async fn begin(&self) -> Result<u64> {
         Ok(0)
     }
@@ -21,32 +92,529 @@ impl DatabaseTransaction for Dummy {
     }
 
     async fn is_deadlock(&self, _tx_id: u64) -> Result<bool> {
-        Ok(true)
+        Ok(false)
     }
 
-    async fn create_user<T>(&self, _tx_id: u64, _params: T) -> Result<User>
+    fn abandon(&self, _tx_id: u64) {}
+
+    async fn create_user<T>(&self, _tx_id: u64, params: T) -> Result<User>
     where
         T: Into<CreateUserParams> + Send,
     {
-        Ok(User {
-            id: 0,
-            username: String::from(""),
-            password: String::from(""),
-            address: String::from(""),
-            age: 10,
-        })
+        let params = params.into();
+        let mut data = self.data.lock().unwrap();
+        data.last_id += 1;
+        let user = User {
+            id: data.last_id,
+            username: params.username,
+            password: params.password,
+            age: params.age,
+            address: params.address,
+            version: 1,
+            deleted_at: None,
+        };
+        data.index(&user);
+        data.users.insert(user.id, user.clone());
+        Ok(user)
     }
 
-    async fn get_user<T>(&self, _tx_id: u64, _id: T) -> Result<Option<User>>
+    async fn get_user<T>(&self, _tx_id: u64, params: T) -> Result<Option<User>>
     where
         T: Into<GetUserParams> + Send,
     {
-        Ok(Some(User {
-            id: 0,
-            username: String::from(""),
-            password: String::from(""),
-            address: String::from(""),
-            age: 10,
-        }))
+        let params = params.into();
+        let data = self.data.lock().unwrap();
+        Ok(data
+            .users
+            .get(&params.id)
+            .filter(|v| params.include_deleted || v.deleted_at.is_none())
+            .cloned())
+    }
+
+    async fn update_user<T>(&self, _tx_id: u64, params: T) -> Result<Option<User>>
+    where
+        T: Into<UpdateUserParams> + Send,
+    {
+        let params = params.into();
+        let mut data = self.data.lock().unwrap();
+        let Some(mut user) = data.live_user(params.id).cloned() else {
+            return Ok(None);
+        };
+        check_version(&user, params.expected_version)?;
+        if let Some(v) = params.username {
+            user.username = v;
+        }
+        if let Some(v) = params.password {
+            user.password = v;
+        }
+        if let Some(v) = params.age {
+            user.age = v;
+        }
+        if let Some(v) = params.address {
+            user.address = v;
+        }
+        user.version += 1;
+        let previous = data.users.insert(user.id, user.clone());
+        if let Some(v) = previous {
+            data.unindex(&v);
+        }
+        data.index(&user);
+        Ok(Some(user))
+    }
+
+    async fn delete_user<T>(&self, _tx_id: u64, params: T) -> Result<bool>
+    where
+        T: Into<DeleteUserParams> + Send,
+    {
+        let params = params.into();
+        let mut data = self.data.lock().unwrap();
+        let Some(user) = data.users.get_mut(&params.id) else {
+            return Ok(false);
+        };
+        if user.deleted_at.is_some() {
+            return Ok(false);
+        }
+        check_version(user, params.expected_version)?;
+        // Kept in the index for the restoration, and skipped by the search.
+        user.deleted_at = Some(Utc::now());
+        user.version += 1;
+        Ok(true)
+    }
+
+    async fn restore_user<T>(&self, _tx_id: u64, params: T) -> Result<Option<User>>
+    where
+        T: Into<RestoreUserParams> + Send,
+    {
+        let params = params.into();
+        let mut data = self.data.lock().unwrap();
+        let Some(user) = data.users.get_mut(&params.id) else {
+            return Ok(None);
+        };
+        check_version(user, params.expected_version)?;
+        if user.deleted_at.take().is_some() {
+            user.version += 1;
+        }
+        Ok(Some(user.clone()))
+    }
+
+    async fn purge_deleted_users<T>(&self, _tx_id: u64, params: T) -> Result<u64>
+    where
+        T: Into<PurgeDeletedUsersParams> + Send,
+    {
+        let params = params.into();
+        let deleted_before = Utc::now() - Duration::from_secs(params.retention);
+        let mut data = self.data.lock().unwrap();
+        let mut purged = data
+            .users
+            .values()
+            .filter(|v| v.deleted_at.is_some_and(|v| v <= deleted_before))
+            .cloned()
+            .collect::<Vec<_>>();
+        purged.sort_by_key(|v| v.deleted_at);
+        purged.truncate(params.limit as usize);
+        for user in &purged {
+            data.users.remove(&user.id);
+            data.unindex(user);
+        }
+        data.responses
+            .retain(|_, (_, _, user_id)| user_id.is_none_or(|v| purged.iter().all(|u| u.id != v)));
+        Ok(purged.len() as u64)
+    }
+
+    async fn list_users<T>(&self, _tx_id: u64, params: T) -> Result<Vec<User>>
+    where
+        T: Into<ListUsersParams> + Send,
+    {
+        let params = params.into();
+        let sort = params.sort;
+        let data = self.data.lock().unwrap();
+        let mut users = data
+            .users
+            .values()
+            .filter(|v| params.filter.matches(v))
+            .filter(|v| {
+                params
+                    .after
+                    .as_ref()
+                    .is_none_or(|after| sort.compare(&sort.key(v), after).is_gt())
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        users.sort_by(|a, b| sort.compare(&sort.key(a), &sort.key(b)));
+        users.truncate(params.limit as usize);
+        Ok(users)
+    }
+
+    async fn search_users<T>(&self, _tx_id: u64, params: T) -> Result<Vec<SearchHit>>
+    where
+        T: Into<SearchUsersParams> + Send,
+    {
+        let params = params.into();
+        if params.terms.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let data = self.data.lock().unwrap();
+        let total = data.users.len() as f64;
+        let mut scores = HashMap::<u64, (f64, usize)>::new();
+        for term in &params.terms {
+            // Users having a word starting with the term, with their occurrences.
+            let mut counts = HashMap::<u64, u32>::new();
+            for (_, users) in data
+                .index
+                .range(term.clone()..)
+                .take_while(|(word, _)| word.starts_with(term.as_str()))
+            {
+                for (id, count) in users {
+                    *counts.entry(*id).or_default() += count;
+                }
+            }
+            // The rarer the term, the more relevant it is.
+            let idf = (1.0 + total / counts.len().max(1) as f64).ln();
+            for (id, count) in counts {
+                let score = scores.entry(id).or_default();
+                score.0 += count as f64 * idf;
+                score.1 += 1;
+            }
+        }
+
+        let mut hits = scores
+            .into_iter()
+            .filter(|(_, (_, matched))| *matched == params.terms.len())
+            .filter_map(|(id, (score, _))| {
+                let user = data.live_user(id)?.clone();
+                Some(SearchHit {
+                    highlights: search::highlights(&user, &params.terms),
+                    user,
+                    score,
+                })
+            })
+            .collect::<Vec<_>>();
+        hits.sort_by(|a, b| b.score.total_cmp(&a.score).then(a.user.id.cmp(&b.user.id)));
+        hits.truncate(params.limit as usize);
+        Ok(hits)
+    }
+
+    async fn get_stored_response<T>(&self, _tx_id: u64, params: T) -> Result<Option<StoredResponse>>
+    where
+        T: Into<GetStoredResponseParams> + Send,
+    {
+        let params = params.into();
+        let data = self.data.lock().unwrap();
+        Ok(match data.responses.get(&params.key) {
+            Some((response, expires_at, _)) if *expires_at > Instant::now() => {
+                Some(response.clone())
+            }
+            _ => None,
+        })
+    }
+
+    async fn put_stored_response<T>(&self, _tx_id: u64, params: T) -> Result<()>
+    where
+        T: Into<PutStoredResponseParams> + Send,
+    {
+        let params = params.into();
+        let now = Instant::now();
+        let mut data = self.data.lock().unwrap();
+        // Like the primary key of the MySQL table, only an expired one is replaced.
+        if matches!(data.responses.get(&params.key), Some((_, v, _)) if *v > now) {
+            return Err(anyhow!("duplicate idempotency key: {}", params.key));
+        }
+        let expires_at = now + Duration::from_secs(params.ttl);
+        data.responses
+            .insert(params.key, (params.response, expires_at, params.user_id));
+        Ok(())
+    }
+
+    async fn purge_stored_responses(&self, _tx_id: u64) -> Result<u64> {
+        let now = Instant::now();
+        let mut data = self.data.lock().unwrap();
+        let count = data.responses.len();
+        data.responses
+            .retain(|_, (_, expires_at, _)| *expires_at > now);
+        Ok((count - data.responses.len()) as u64)
+    }
+}
+
+fn check_version(user: &User, expected_version: Option<u64>) -> Result<()> {
+    match expected_version {
+        Some(v) if v != user.version => Err(Conflict {
+            id: user.id,
+            expected_version: v,
+            actual_version: user.version,
+        })?,
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::core::entity::{UserFilter, UserKey, UserSort, UserSortField};
+    use crate::core::search::tokenize;
+
+    async fn dummy(users: &[(&str, u16, &str)]) -> Dummy {
+        let dummy = Dummy::default();
+        for (username, age, address) in users {
+            let params = CreateUserParams {
+                username: username.to_string(),
+                password: String::from("password").into(),
+                age: *age,
+                address: address.to_string(),
+            };
+            dummy.create_user(0, params).await.unwrap();
+        }
+        dummy
+    }
+
+    async fn list(dummy: &Dummy, filter: &UserFilter, sort: UserSort) -> Vec<u64> {
+        // Pages of two, to go through the keyset continuation.
+        let mut ids = Vec::new();
+        let mut after = None;
+        loop {
+            let params = ListUsersParams {
+                filter: filter.clone(),
+                sort,
+                after,
+                limit: 2,
+            };
+            let users = dummy.list_users(0, params).await.unwrap();
+            ids.extend(users.iter().map(|v| v.id));
+            match users.last() {
+                Some(v) if users.len() == 2 => after = Some(sort.key(v)),
+                _ => return ids,
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn filter_ignores_case() {
+        let dummy = dummy(&[
+            ("Alice", 30, "1 Main Street"),
+            ("alan", 17, "2 main street"),
+            ("Bob", 40, "3 High Street"),
+        ])
+        .await;
+        let filter = UserFilter {
+            username_prefix: Some(String::from("AL")),
+            ..Default::default()
+        };
+        assert_eq!(list(&dummy, &filter, UserSort::default()).await, [1, 2]);
+        let filter = UserFilter {
+            address_contains: Some(String::from("MAIN")),
+            min_age: Some(18),
+            ..Default::default()
+        };
+        assert_eq!(list(&dummy, &filter, UserSort::default()).await, [1]);
+    }
+
+    #[tokio::test]
+    async fn filter_skips_deleted_users() {
+        let dummy = dummy(&[("a", 1, "x"), ("b", 2, "x"), ("c", 3, "x")]).await;
+        let params = DeleteUserParams {
+            id: 2,
+            expected_version: None,
+        };
+        assert!(dummy.delete_user(0, params).await.unwrap());
+
+        let mut filter = UserFilter::default();
+        assert_eq!(list(&dummy, &filter, UserSort::default()).await, [1, 3]);
+        filter.include_deleted = true;
+        assert_eq!(list(&dummy, &filter, UserSort::default()).await, [1, 2, 3]);
+    }
+
+    #[tokio::test]
+    async fn keyset_pages_break_ties_by_id() {
+        let dummy = dummy(&[
+            ("carol", 30, "x"),
+            ("alice", 20, "x"),
+            ("bob", 30, "x"),
+            ("alice", 30, "x"),
+            ("dave", 20, "x"),
+        ])
+        .await;
+        let filter = UserFilter::default();
+        let sort = |field, descending| UserSort { field, descending };
+
+        assert_eq!(
+            list(&dummy, &filter, sort(UserSortField::Username, false)).await,
+            [2, 4, 3, 1, 5]
+        );
+        assert_eq!(
+            list(&dummy, &filter, sort(UserSortField::Age, false)).await,
+            [2, 5, 1, 3, 4]
+        );
+        assert_eq!(
+            list(&dummy, &filter, sort(UserSortField::Age, true)).await,
+            [4, 3, 1, 5, 2]
+        );
+        assert_eq!(
+            list(&dummy, &filter, sort(UserSortField::Id, true)).await,
+            [5, 4, 3, 2, 1]
+        );
+    }
+
+    #[tokio::test]
+    async fn username_order_ignores_case() {
+        let dummy = dummy(&[
+            ("bob", 1, "x"),
+            ("Alice", 1, "x"),
+            ("alice", 1, "x"),
+            ("Carol", 1, "x"),
+            ("adam", 1, "x"),
+        ])
+        .await;
+        let filter = UserFilter::default();
+        let sort = |descending| UserSort {
+            field: UserSortField::Username,
+            descending,
+        };
+        assert_eq!(list(&dummy, &filter, sort(false)).await, [5, 2, 3, 1, 4]);
+        assert_eq!(list(&dummy, &filter, sort(true)).await, [4, 1, 3, 2, 5]);
+    }
+
+    #[tokio::test]
+    async fn keyset_continues_after_the_key() {
+        let dummy = dummy(&[("a", 1, "x"), ("b", 2, "x"), ("c", 3, "x")]).await;
+        let params = ListUsersParams {
+            filter: UserFilter::default(),
+            sort: UserSort::default(),
+            after: Some(UserKey::Id(1)),
+            limit: 10,
+        };
+        let users = dummy.list_users(0, params).await.unwrap();
+        assert_eq!(users.iter().map(|v| v.id).collect::<Vec<_>>(), [2, 3]);
+    }
+
+    async fn search(dummy: &Dummy, query: &str) -> Vec<u64> {
+        let params = SearchUsersParams {
+            terms: tokenize(query),
+            limit: 10,
+        };
+        let hits = dummy.search_users(0, params).await.unwrap();
+        hits.iter().map(|v| v.user.id).collect()
+    }
+
+    #[tokio::test]
+    async fn search_requires_every_term() {
+        let dummy = dummy(&[
+            ("alice", 1, "Main Street"),
+            ("bob", 1, "Main Street"),
+            ("alice", 1, "High Street"),
+        ])
+        .await;
+        assert_eq!(search(&dummy, "alice main").await, [1]);
+        assert_eq!(search(&dummy, "Str").await, [1, 2, 3]);
+        assert!(search(&dummy, "alice nowhere").await.is_empty());
+        assert!(search(&dummy, "").await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn search_ranks_rare_terms_higher() {
+        let dummy = dummy(&[
+            ("a", 1, "Main Street Street"),
+            ("b", 1, "Main Main Street"),
+            ("c", 1, "Oak Street"),
+            ("d", 1, "Elm Street"),
+        ])
+        .await;
+        // Both match twice, but main is the rarer one.
+        assert_eq!(search(&dummy, "main street").await, [2, 1]);
+        // More occurrences of the same term rank higher, the ties by the id.
+        assert_eq!(search(&dummy, "street").await, [1, 2, 3, 4]);
+
+        let params = SearchUsersParams {
+            terms: tokenize("street"),
+            limit: 1,
+        };
+        let hits = dummy.search_users(0, params).await.unwrap();
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].highlights[0].ranges, [5..11, 12..18]);
+    }
+
+    #[tokio::test]
+    async fn search_follows_updates_and_deletes() {
+        let dummy = dummy(&[("alice", 1, "Main Street"), ("bob", 1, "Main Street")]).await;
+        let params = UpdateUserParams {
+            id: 1,
+            username: Some(String::from("carol")),
+            password: None,
+            age: None,
+            address: None,
+            expected_version: None,
+        };
+        dummy.update_user(0, params).await.unwrap();
+        assert!(search(&dummy, "alice").await.is_empty());
+        assert_eq!(search(&dummy, "carol").await, [1]);
+
+        let params = DeleteUserParams {
+            id: 2,
+            expected_version: None,
+        };
+        dummy.delete_user(0, params).await.unwrap();
+        assert_eq!(search(&dummy, "main").await, [1]);
+        let params = RestoreUserParams {
+            id: 2,
+            expected_version: None,
+        };
+        dummy.restore_user(0, params).await.unwrap();
+        assert_eq!(search(&dummy, "main").await, [1, 2]);
+    }
+
+    #[tokio::test]
+    async fn purge_deletes_in_batches_with_stored_responses() {
+        let dummy = dummy(&[("a", 1, "x"), ("b", 2, "x"), ("c", 3, "x")]).await;
+        for id in [1, 2] {
+            let params = DeleteUserParams {
+                id,
+                expected_version: None,
+            };
+            dummy.delete_user(0, params).await.unwrap();
+            let params = PutStoredResponseParams {
+                key: format!("key{id}"),
+                response: StoredResponse {
+                    fingerprint: String::new(),
+                    status: 200,
+                    headers: Vec::new(),
+                    body: Vec::new(),
+                },
+                ttl: 60,
+                user_id: Some(id),
+            };
+            dummy.put_stored_response(0, params).await.unwrap();
+        }
+        let purge = |limit| PurgeDeletedUsersParams {
+            retention: 0,
+            limit,
+        };
+        let stored = |key: &str| GetStoredResponseParams {
+            key: key.to_string(),
+        };
+
+        assert_eq!(dummy.purge_deleted_users(0, purge(1)).await.unwrap(), 1);
+        assert!(dummy
+            .get_stored_response(0, stored("key1"))
+            .await
+            .unwrap()
+            .is_none());
+        assert!(dummy
+            .get_stored_response(0, stored("key2"))
+            .await
+            .unwrap()
+            .is_some());
+        assert_eq!(dummy.purge_deleted_users(0, purge(1)).await.unwrap(), 1);
+        assert!(dummy
+            .get_stored_response(0, stored("key2"))
+            .await
+            .unwrap()
+            .is_none());
+        assert_eq!(dummy.purge_deleted_users(0, purge(1)).await.unwrap(), 0);
+
+        let params = GetUserParams {
+            id: 3,
+            include_deleted: true,
+        };
+        assert!(dummy.get_user(0, params).await.unwrap().is_some());
     }
 }
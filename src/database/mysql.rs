@@ -1,25 +1,43 @@
-use crate::core::entity::{CreateUserParams, DatabaseTransaction, GetUserParams, User};
+use crate::core::entity::{
+    Conflict, CreateUserParams, DatabaseTransaction, DeleteUserParams, GetStoredResponseParams,
+    GetUserParams, ListUsersParams, PurgeDeletedUsersParams, PutStoredResponseParams,
+    RestoreUserParams, SearchHit, SearchUsersParams, StoredResponse, UpdateUserParams, User,
+    UserKey, UserSortField,
+};
+use crate::core::search;
+use crate::core::secret::Secret;
 use crate::database::Configuration;
+use crate::metrics::METRICS;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fmt::Debug;
 use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 use std::time::Duration;
 
 use anyhow::{anyhow, Context, Result};
 use async_trait::async_trait;
+use chrono::NaiveDateTime;
 use futures::lock::Mutex;
 use mysql_async::prelude::{FromRow, Queryable, StatementLike};
-use mysql_async::{params, Params, Row, TxOpts};
+use mysql_async::{params, IsolationLevel, Params, Row, TxOpts, Value};
+use prometheus::core::{Collector, Desc};
+use prometheus::proto::MetricFamily;
+use prometheus::IntGauge;
 use scopeguard::ScopeGuard;
+use tracing::Instrument;
 
 #[derive(Debug)]
 pub struct Client {
     counter: AtomicU64,
     pool: mysql_async::Pool,
     map: Mutex<HashMap<u64, Transaction>>,
+    // Abandoned while in use. They are dropped once put back.
+    abandoned: std::sync::Mutex<HashSet<u64>>,
 }
 
+type TransactionGuard<'a> = ScopeGuard<Transaction, Box<dyn FnOnce(Transaction) + Send + 'a>>;
+
 #[derive(Debug)]
 struct Transaction {
     id: u64,
@@ -30,10 +48,11 @@ struct Transaction {
 impl Transaction {
     async fn exec_drop<'a: 'b, 'b, S, P>(&'a mut self, stmt: S, params: P) -> Result<()>
     where
-        S: StatementLike + 'b,
+        S: StatementLike + AsRef<str> + 'b,
         P: Into<Params> + Send + 'b,
     {
-        let v = self.handle.exec_drop(stmt, params).await;
+        let span = statement_span(self.id, stmt.as_ref());
+        let v = self.handle.exec_drop(stmt, params).instrument(span).await;
         if v.is_ok() {
             self.deadlock = false;
             return Ok(v?);
@@ -48,13 +67,14 @@ impl Transaction {
         f: F,
     ) -> Result<Vec<U>>
     where
-        S: StatementLike + 'b,
+        S: StatementLike + AsRef<str> + 'b,
         P: Into<Params> + Send + 'b,
         T: FromRow + Send + 'static,
         F: FnMut(T) -> U + Send + 'a,
         U: Send + 'a,
     {
-        let v = self.handle.exec_map(stmt, params, f).await;
+        let span = statement_span(self.id, stmt.as_ref());
+        let v = self.handle.exec_map(stmt, params, f).instrument(span).await;
         if v.is_ok() {
             self.deadlock = false;
             return Ok(v?);
@@ -67,19 +87,14 @@ impl Transaction {
         result: Result<T, mysql_async::Error>,
     ) -> Result<T, mysql_async::Error> {
         let err = result.err().unwrap();
-        let err_code = get_mysql_error_code(&err);
-        if err_code.is_some() && err_code.unwrap() == MYSQL_DEADLOCK_ERROR_CODE {
-            self.deadlock = true;
-        } else {
-            self.deadlock = false;
-        }
+        self.deadlock = get_mysql_error_code(&err) == Some(MYSQL_DEADLOCK_ERROR_CODE);
 
-        Err(err.into())
+        Err(err)
     }
 }
 
 impl Client {
-    pub fn new(config: Configuration) -> Self {
+    pub fn new(config: Configuration) -> Result<Self> {
         let counter = AtomicU64::new(0);
         let builder = mysql_async::OptsBuilder::default()
             .ip_or_hostname(config.host)
@@ -93,8 +108,16 @@ impl Client {
         let opts = mysql_async::Opts::from(builder);
         let pool = mysql_async::Pool::new(opts);
         let map = Mutex::new(HashMap::new());
+        METRICS
+            .register(Box::new(PoolCollector::new(pool.metrics())))
+            .context("failed to register the connection pool metrics")?;
 
-        Self { counter, pool, map }
+        Ok(Self {
+            counter,
+            pool,
+            map,
+            abandoned: Default::default(),
+        })
     }
 
     fn get_transaction(&self, tx_id: u64) -> Option<Transaction> {
@@ -108,10 +131,7 @@ impl Client {
         }
     }
 
-    fn get_transaction_guard(
-        &self,
-        tx_id: u64,
-    ) -> Result<ScopeGuard<Transaction, Box<dyn FnOnce(Transaction) + Send + '_>>> {
+    fn get_transaction_guard(&self, tx_id: u64) -> Result<TransactionGuard<'_>> {
         log::debug!("get_transaction_guard invoked: tx_id = {tx_id}");
 
         match self.get_transaction(tx_id) {
@@ -128,6 +148,10 @@ impl Client {
 
     fn put_transaction(&self, tx: Transaction) {
         log::debug!("put_transaction invoked: tx_id = {}", tx.id);
+        if self.abandoned.lock().unwrap().remove(&tx.id) {
+            drop_transaction(tx);
+            return;
+        }
 
         loop {
             match self.map.try_lock() {
@@ -141,6 +165,83 @@ impl Client {
     }
 }
 
+// mysql_async rolls back a dropped transaction when its connection goes back to
+// the pool.
+fn drop_transaction(tx: Transaction) {
+    log::warn!("dropping an abandoned transaction: tx_id = {}", tx.id);
+    METRICS.mysql_open_transactions.dec();
+    drop(tx);
+}
+
+fn statement_span(tx_id: u64, stmt: &str) -> tracing::Span {
+    tracing::info_span!(
+        "mysql_statement",
+        otel.kind = "client",
+        otel.name = stmt.split_whitespace().next().unwrap_or_default(),
+        db.system = "mysql",
+        db.statement = stmt,
+        tx_id,
+    )
+}
+
+// Reads the statistics of the connection pool whenever the metrics are scraped.
+struct PoolCollector {
+    metrics: Arc<mysql_async::Metrics>,
+    connections: IntGauge,
+    idle_connections: IntGauge,
+    wait_requests: IntGauge,
+}
+
+impl PoolCollector {
+    fn new(metrics: Arc<mysql_async::Metrics>) -> Self {
+        Self {
+            metrics,
+            connections: IntGauge::new(
+                "mysql_pool_connections",
+                "Number of connections to the MySQL server.",
+            )
+            .unwrap(),
+            idle_connections: IntGauge::new(
+                "mysql_pool_idle_connections",
+                "Number of idle connections in the MySQL connection pool.",
+            )
+            .unwrap(),
+            wait_requests: IntGauge::new(
+                "mysql_pool_wait_requests",
+                "Number of requests waiting for a MySQL connection.",
+            )
+            .unwrap(),
+        }
+    }
+
+    fn gauges(&self) -> [&IntGauge; 3] {
+        [
+            &self.connections,
+            &self.idle_connections,
+            &self.wait_requests,
+        ]
+    }
+}
+
+impl Collector for PoolCollector {
+    fn desc(&self) -> Vec<&Desc> {
+        self.gauges().into_iter().flat_map(|v| v.desc()).collect()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let load = |v: &std::sync::atomic::AtomicUsize| v.load(Ordering::Relaxed) as i64;
+        self.connections.set(load(&self.metrics.connection_count));
+        self.idle_connections
+            .set(load(&self.metrics.connections_in_pool));
+        self.wait_requests
+            .set(load(&self.metrics.active_wait_requests));
+        self.gauges()
+            .into_iter()
+            .flat_map(|v| v.collect())
+            .collect()
+    }
+}
+
 const MYSQL_DEADLOCK_ERROR_CODE: u16 = 1213;
 
 fn get_mysql_error_code(err: &mysql_async::Error) -> Option<u16> {
@@ -152,11 +253,67 @@ fn get_mysql_error_code(err: &mysql_async::Error) -> Option<u16> {
 
 #[async_trait]
 impl DatabaseTransaction for Client {
+    async fn ping(&self) -> Result<()> {
+        log::debug!("ping invoked");
+        let mut conn = self
+            .pool
+            .get_conn()
+            .await
+            .context("failed to get a database connection")?;
+        conn.ping().await.context("failed to ping the database")
+    }
+
+    async fn check_schema(&self) -> Result<()> {
+        log::debug!("check_schema invoked");
+        let mut conn = self
+            .pool
+            .get_conn()
+            .await
+            .context("failed to get a database connection")?;
+        let queries = [
+            "SELECT `id`, `username`, `password`, `age`, `address`, `version`, `deleted_at` \
+             FROM `users` LIMIT 0",
+            "SELECT `key`, `fingerprint`, `status`, `headers`, `body`, `expires_at`, `user_id` \
+             FROM `idempotency_keys` LIMIT 0",
+            // Fails without the FULLTEXT index on these columns.
+            "SELECT `id` FROM `users` \
+             WHERE MATCH (`username`, `address`) AGAINST ('' IN BOOLEAN MODE) LIMIT 0",
+        ];
+        for query in queries {
+            conn.query_drop(query)
+                .await
+                .context("database schema is not up to date, apply the migrations")?;
+        }
+        Ok(())
+    }
+
+    async fn close(&self) -> Result<()> {
+        log::debug!("close invoked");
+        let transactions: Vec<Transaction> = self.map.lock().await.drain().map(|v| v.1).collect();
+        for tx in transactions {
+            log::warn!("rolling back an open transaction: tx_id = {}", tx.id);
+            METRICS.mysql_open_transactions.dec();
+            if let Err(err) = tx.handle.rollback().await {
+                log::error!("failed to rollback a database transaction: {err:?}");
+            }
+        }
+
+        self.pool
+            .clone()
+            .disconnect()
+            .await
+            .context("failed to disconnect from the database")
+    }
+
     async fn begin(&self) -> Result<u64> {
         log::debug!("begin invoked");
+        // The locking reads rely on the gap locks of this level, whatever the
+        // server default is.
+        let mut options = TxOpts::default();
+        options.with_isolation_level(IsolationLevel::RepeatableRead);
         let tx = self
             .pool
-            .start_transaction(TxOpts::default())
+            .start_transaction(options)
             .await
             .context("failed to start a database transaction")?;
         let tx_id = self.counter.fetch_add(1, Ordering::SeqCst);
@@ -165,13 +322,17 @@ impl DatabaseTransaction for Client {
             handle: tx,
             deadlock: false,
         });
+        METRICS.mysql_open_transactions.inc();
         Ok(tx_id)
     }
 
     async fn commit(&self, tx_id: u64) -> Result<()> {
         log::debug!("commit invoked: tx_id = {tx_id}");
         match self.get_transaction(tx_id) {
-            Some(tx) => Ok(tx.handle.commit().await?),
+            Some(tx) => {
+                METRICS.mysql_open_transactions.dec();
+                Ok(tx.handle.commit().await?)
+            }
             None => Err(anyhow!("unknown transaction id: {tx_id}")),
         }
     }
@@ -179,7 +340,10 @@ impl DatabaseTransaction for Client {
     async fn rollback(&self, tx_id: u64) -> Result<()> {
         log::debug!("rollback invoked: tx_id = {tx_id}");
         match self.get_transaction(tx_id) {
-            Some(tx) => Ok(tx.handle.rollback().await?),
+            Some(tx) => {
+                METRICS.mysql_open_transactions.dec();
+                Ok(tx.handle.rollback().await?)
+            }
             None => Err(anyhow!("unknown transaction id: {tx_id}")),
         }
     }
@@ -198,6 +362,17 @@ impl DatabaseTransaction for Client {
         }
     }
 
+    fn abandon(&self, tx_id: u64) {
+        log::debug!("abandon invoked: tx_id = {tx_id}");
+        match self.get_transaction(tx_id) {
+            Some(tx) => drop_transaction(tx),
+            // In use by a statement, e.g., the one that has timed out.
+            None => {
+                self.abandoned.lock().unwrap().insert(tx_id);
+            }
+        }
+    }
+
     async fn create_user<T>(&self, tx_id: u64, params: T) -> Result<User>
     where
         T: Into<CreateUserParams> + Send,
@@ -206,13 +381,13 @@ impl DatabaseTransaction for Client {
         log::debug!("create_user: tx_id = {tx_id}, params = {params:?}");
 
         let mut tx = self.get_transaction_guard(tx_id)?;
-        let query = "INSERT INTO `users` (`username`, `password`, `age`, `address`) \
-                     VALUES (:username, :password, :age, :address)";
+        let query = "INSERT INTO `users` (`username`, `password`, `age`, `address`, `version`) \
+                     VALUES (:username, :password, :age, :address, 1)";
         tx.exec_drop(
             query,
             params! {
                 "username" => &params.username,
-                "password" => &params.password,
+                "password" => params.password.expose(),
                 "age" => params.age,
                 "address" => &params.address,
             },
@@ -229,6 +404,8 @@ impl DatabaseTransaction for Client {
             password: params.password,
             age: params.age,
             address: params.address,
+            version: 1,
+            deleted_at: None,
         })
     }
 
@@ -240,27 +417,418 @@ impl DatabaseTransaction for Client {
         log::debug!("get_user: tx_id = {}, id = {}", tx_id, params.id);
 
         let mut tx = self.get_transaction_guard(tx_id)?;
-        let query =
-            r"SELECT `id`, `username`, `password`, `age`, `address` FROM `users` WHERE `id` = :id";
-        let mut users = tx
+        select_user(&mut tx, params.id, params.include_deleted).await
+    }
+
+    async fn update_user<T>(&self, tx_id: u64, params: T) -> Result<Option<User>>
+    where
+        T: Into<UpdateUserParams> + Send,
+    {
+        let params = params.into();
+        log::debug!("update_user: tx_id = {tx_id}, params = {params:?}");
+
+        let mut tx = self.get_transaction_guard(tx_id)?;
+        let query = "UPDATE `users` SET \
+                     `username` = COALESCE(:username, `username`), \
+                     `password` = COALESCE(:password, `password`), \
+                     `age` = COALESCE(:age, `age`), \
+                     `address` = COALESCE(:address, `address`), \
+                     `version` = `version` + 1 \
+                     WHERE `id` = :id AND `deleted_at` IS NULL \
+                     AND (:version IS NULL OR `version` = :version)";
+        tx.exec_drop(
+            query,
+            params! {
+                "id" => params.id,
+                "username" => &params.username,
+                "password" => params.password.as_ref().map(|v| v.expose()),
+                "age" => params.age,
+                "address" => &params.address,
+                "version" => params.expected_version,
+            },
+        )
+        .await?;
+        // The version always changes, so no affected row means either the user
+        // does not exist or it has another version.
+        let updated = tx.handle.affected_rows() > 0;
+        let user = select_user(&mut tx, params.id, false).await?;
+        match user {
+            Some(user) if !updated => Err(conflict(&user, params.expected_version))?,
+            user => Ok(user),
+        }
+    }
+
+    async fn delete_user<T>(&self, tx_id: u64, params: T) -> Result<bool>
+    where
+        T: Into<DeleteUserParams> + Send,
+    {
+        let params = params.into();
+        log::debug!("delete_user: tx_id = {}, id = {}", tx_id, params.id);
+
+        let mut tx = self.get_transaction_guard(tx_id)?;
+        let query = "UPDATE `users` SET \
+                     `deleted_at` = UTC_TIMESTAMP(6), \
+                     `version` = `version` + 1 \
+                     WHERE `id` = :id AND `deleted_at` IS NULL \
+                     AND (:version IS NULL OR `version` = :version)";
+        tx.exec_drop(
+            query,
+            params! {
+                "id" => params.id,
+                "version" => params.expected_version,
+            },
+        )
+        .await?;
+        if tx.handle.affected_rows() > 0 {
+            return Ok(true);
+        }
+        match select_user(&mut tx, params.id, false).await? {
+            Some(user) => Err(conflict(&user, params.expected_version))?,
+            None => Ok(false),
+        }
+    }
+
+    async fn restore_user<T>(&self, tx_id: u64, params: T) -> Result<Option<User>>
+    where
+        T: Into<RestoreUserParams> + Send,
+    {
+        let params = params.into();
+        log::debug!("restore_user: tx_id = {}, id = {}", tx_id, params.id);
+
+        let mut tx = self.get_transaction_guard(tx_id)?;
+        let query = "UPDATE `users` SET \
+                     `deleted_at` = NULL, \
+                     `version` = `version` + 1 \
+                     WHERE `id` = :id AND `deleted_at` IS NOT NULL \
+                     AND (:version IS NULL OR `version` = :version)";
+        tx.exec_drop(
+            query,
+            params! {
+                "id" => params.id,
+                "version" => params.expected_version,
+            },
+        )
+        .await?;
+        let restored = tx.handle.affected_rows() > 0;
+        let user = select_user(&mut tx, params.id, true).await?;
+        match user {
+            Some(user)
+                if !restored
+                    && (user.deleted_at.is_some()
+                        || params.expected_version.is_some_and(|v| v != user.version)) =>
+            {
+                Err(conflict(&user, params.expected_version))?
+            }
+            user => Ok(user),
+        }
+    }
+
+    async fn purge_deleted_users<T>(&self, tx_id: u64, params: T) -> Result<u64>
+    where
+        T: Into<PurgeDeletedUsersParams> + Send,
+    {
+        let params = params.into();
+        log::debug!("purge_deleted_users: tx_id = {tx_id}, params = {params:?}");
+
+        let mut tx = self.get_transaction_guard(tx_id)?;
+        let query = "SELECT `id` FROM `users` \
+                     WHERE `deleted_at` <= UTC_TIMESTAMP(6) - INTERVAL :retention SECOND \
+                     ORDER BY `deleted_at` LIMIT :limit FOR UPDATE";
+        let values = params! { "retention" => params.retention, "limit" => params.limit };
+        let ids = tx
+            .exec_map(query, values, |row: Row| row.get::<u64, _>("id").unwrap())
+            .await?;
+        if ids.is_empty() {
+            return Ok(0);
+        }
+
+        let names = (0..ids.len())
+            .map(|i| format!(":id{i}"))
+            .collect::<Vec<_>>();
+        let values = ids
+            .iter()
+            .enumerate()
+            .map(|(i, id)| (format!("id{i}"), Value::from(id)))
+            .collect::<Vec<_>>();
+        let query = format!(
+            "DELETE FROM `idempotency_keys` WHERE `user_id` IN ({})",
+            names.join(", ")
+        );
+        tx.exec_drop(query, Params::from(values.clone())).await?;
+        let query = format!("DELETE FROM `users` WHERE `id` IN ({})", names.join(", "));
+        tx.exec_drop(query, Params::from(values)).await?;
+        Ok(tx.handle.affected_rows())
+    }
+
+    async fn list_users<T>(&self, tx_id: u64, params: T) -> Result<Vec<User>>
+    where
+        T: Into<ListUsersParams> + Send,
+    {
+        let params = params.into();
+        log::debug!("list_users: tx_id = {tx_id}, params = {params:?}");
+
+        let mut tx = self.get_transaction_guard(tx_id)?;
+        let (query, values) = list_users_query(&params)?;
+        tx.exec_map(query, Params::from(values), to_user).await
+    }
+
+    async fn search_users<T>(&self, tx_id: u64, params: T) -> Result<Vec<SearchHit>>
+    where
+        T: Into<SearchUsersParams> + Send,
+    {
+        let params = params.into();
+        log::debug!("search_users: tx_id = {tx_id}, params = {params:?}");
+        if params.terms.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let mut tx = self.get_transaction_guard(tx_id)?;
+        // Every term is required and matches the words starting with it. The
+        // terms consist of letters and digits only, so none is an operator. The
+        // ones shorter than `innodb_ft_min_token_size` or in the stopword list
+        // are ignored by MySQL.
+        let against = params
+            .terms
+            .iter()
+            .map(|v| format!("+{v}*"))
+            .collect::<Vec<_>>()
+            .join(" ");
+        let query = "SELECT `id`, `username`, `password`, `age`, `address`, `version`, \
+                     `deleted_at`, \
+                     MATCH (`username`, `address`) AGAINST (:against IN BOOLEAN MODE) AS `score` \
+                     FROM `users` \
+                     WHERE MATCH (`username`, `address`) AGAINST (:against IN BOOLEAN MODE) \
+                     AND `deleted_at` IS NULL \
+                     ORDER BY `score` DESC, `id` LIMIT :limit";
+        let rows = tx
             .exec_map(
                 query,
                 params! {
-                    "id" => params.id,
+                    "against" => against,
+                    "limit" => params.limit,
                 },
-                |row: Row| User {
-                    id: row.get("id").unwrap(),
-                    username: row.get("username").unwrap(),
-                    password: row.get("password").unwrap(),
-                    age: row.get("age").unwrap(),
-                    address: row.get("address").unwrap(),
+                |row: Row| {
+                    let score: f64 = row.get("score").unwrap();
+                    (to_user(row), score)
                 },
             )
             .await?;
-        if users.is_empty() {
-            Ok(None)
-        } else {
-            Ok(Some(users.remove(0)))
+
+        Ok(rows
+            .into_iter()
+            .map(|(user, score)| SearchHit {
+                highlights: search::highlights(&user, &params.terms),
+                user,
+                score,
+            })
+            .collect())
+    }
+
+    async fn get_stored_response<T>(&self, tx_id: u64, params: T) -> Result<Option<StoredResponse>>
+    where
+        T: Into<GetStoredResponseParams> + Send,
+    {
+        let params = params.into();
+        log::debug!("get_stored_response: tx_id = {tx_id}, params = {params:?}");
+
+        let mut tx = self.get_transaction_guard(tx_id)?;
+        // Locks the key even if it does not exist yet. The concurrent requests
+        // with the same key then deadlock on the insert, and the retry of the
+        // loser finds the response of the winner.
+        let query = "SELECT `fingerprint`, `status`, `headers`, `body` FROM `idempotency_keys` \
+                     WHERE `key` = :key AND `expires_at` > UTC_TIMESTAMP(6) FOR UPDATE";
+        let rows = tx
+            .exec_map(query, params! { "key" => &params.key }, |row: Row| row)
+            .await?;
+        let Some(row) = rows.into_iter().next() else {
+            return Ok(None);
+        };
+        let headers: String = row.get("headers").unwrap();
+        Ok(Some(StoredResponse {
+            fingerprint: row.get("fingerprint").unwrap(),
+            status: row.get("status").unwrap(),
+            headers: serde_json::from_str(&headers)
+                .context(format!("invalid stored headers: key = {}", params.key))?,
+            body: row.get("body").unwrap(),
+        }))
+    }
+
+    async fn put_stored_response<T>(&self, tx_id: u64, params: T) -> Result<()>
+    where
+        T: Into<PutStoredResponseParams> + Send,
+    {
+        let params = params.into();
+        log::debug!(
+            "put_stored_response: tx_id = {}, key = {}, status = {}",
+            tx_id,
+            params.key,
+            params.response.status
+        );
+
+        let mut tx = self.get_transaction_guard(tx_id)?;
+        // Only an expired response may be replaced. A live one means that the
+        // lock of `get_stored_response` did not hold, and the insert fails on
+        // the primary key instead of overwriting it.
+        let query = "DELETE FROM `idempotency_keys` \
+                     WHERE `key` = :key AND `expires_at` <= UTC_TIMESTAMP(6)";
+        tx.exec_drop(query, params! { "key" => &params.key })
+            .await?;
+        let query = "INSERT INTO `idempotency_keys` \
+                     (`key`, `fingerprint`, `status`, `headers`, `body`, `expires_at`, `user_id`) \
+                     VALUES (:key, :fingerprint, :status, :headers, :body, \
+                     UTC_TIMESTAMP(6) + INTERVAL :ttl SECOND, :user_id)";
+        let response = params.response;
+        tx.exec_drop(
+            query,
+            params! {
+                "key" => &params.key,
+                "fingerprint" => response.fingerprint,
+                "status" => response.status,
+                "headers" => serde_json::to_string(&response.headers)?,
+                "body" => response.body,
+                "ttl" => params.ttl,
+                "user_id" => params.user_id,
+            },
+        )
+        .await
+    }
+
+    async fn purge_stored_responses(&self, tx_id: u64) -> Result<u64> {
+        log::debug!("purge_stored_responses: tx_id = {tx_id}");
+
+        let mut tx = self.get_transaction_guard(tx_id)?;
+        let query = "DELETE FROM `idempotency_keys` WHERE `expires_at` <= UTC_TIMESTAMP(6)";
+        tx.exec_drop(query, ()).await?;
+        Ok(tx.handle.affected_rows())
+    }
+}
+
+// Builds a keyset query, which seeks to the position instead of skipping the
+// previous pages. The indexes on (`username`, `id`) and (`age`, `id`) serve the
+// sort orders other than the id.
+fn list_users_query(params: &ListUsersParams) -> Result<(String, Vec<(String, Value)>)> {
+    let filter = &params.filter;
+    let mut conditions = Vec::new();
+    let mut values: Vec<(String, Value)> = Vec::new();
+    if !filter.include_deleted {
+        conditions.push(String::from("`deleted_at` IS NULL"));
+    }
+    if let Some(v) = &filter.username_prefix {
+        conditions.push(String::from("`username` LIKE :username_prefix"));
+        values.push((
+            "username_prefix".into(),
+            format!("{}%", escape_like(v)).into(),
+        ));
+    }
+    if let Some(v) = filter.min_age {
+        conditions.push(String::from("`age` >= :min_age"));
+        values.push(("min_age".into(), v.into()));
+    }
+    if let Some(v) = filter.max_age {
+        conditions.push(String::from("`age` <= :max_age"));
+        values.push(("max_age".into(), v.into()));
+    }
+    if let Some(v) = &filter.address_contains {
+        conditions.push(String::from("`address` LIKE :address_contains"));
+        values.push((
+            "address_contains".into(),
+            format!("%{}%", escape_like(v)).into(),
+        ));
+    }
+
+    let sort = params.sort;
+    let (operator, direction) = if sort.descending {
+        ("<", "DESC")
+    } else {
+        (">", "ASC")
+    };
+    let column = match sort.field {
+        UserSortField::Id => "`id`",
+        UserSortField::Username => "`username`",
+        UserSortField::Age => "`age`",
+    };
+    let after_value = match (&params.after, sort.field) {
+        (None, _) => None,
+        (Some(UserKey::Id(id)), UserSortField::Id) => {
+            values.push(("after_id".into(), (*id).into()));
+            conditions.push(format!("`id` {operator} :after_id"));
+            None
+        }
+        (Some(UserKey::Username(v, id)), UserSortField::Username) => Some((Value::from(v), id)),
+        (Some(UserKey::Age(v, id)), UserSortField::Age) => Some((Value::from(v), id)),
+        (Some(v), _) => {
+            return Err(anyhow!(
+                "position is not in the sort order: {v:?}, {sort:?}"
+            ))
         }
+    };
+    if let Some((value, id)) = after_value {
+        values.push(("after_value".into(), value));
+        values.push(("after_id".into(), (*id).into()));
+        conditions.push(format!(
+            "({column}, `id`) {operator} (:after_value, :after_id)"
+        ));
+    }
+
+    let order_by = match sort.field {
+        UserSortField::Id => format!("`id` {direction}"),
+        _ => format!("{column} {direction}, `id` {direction}"),
+    };
+    let where_clause = if conditions.is_empty() {
+        String::new()
+    } else {
+        format!("WHERE {} ", conditions.join(" AND "))
+    };
+    values.push(("limit".into(), params.limit.into()));
+    let query = format!(
+        "SELECT `id`, `username`, `password`, `age`, `address`, `version`, `deleted_at` \
+         FROM `users` {where_clause}ORDER BY {order_by} LIMIT :limit"
+    );
+    Ok((query, values))
+}
+
+// Makes the wildcards of LIKE match themselves, with the default escape character.
+fn escape_like(v: &str) -> String {
+    v.replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
+async fn select_user(tx: &mut Transaction, id: u64, include_deleted: bool) -> Result<Option<User>> {
+    let query = "SELECT `id`, `username`, `password`, `age`, `address`, `version`, `deleted_at` \
+                 FROM `users` WHERE `id` = :id AND (:include_deleted OR `deleted_at` IS NULL)";
+    let params = params! {
+        "id" => id,
+        "include_deleted" => include_deleted,
+    };
+    let mut users = tx.exec_map(query, params, to_user).await?;
+    if users.is_empty() {
+        Ok(None)
+    } else {
+        Ok(Some(users.remove(0)))
+    }
+}
+
+fn to_user(row: Row) -> User {
+    User {
+        id: row.get("id").unwrap(),
+        username: row.get("username").unwrap(),
+        password: Secret::new(row.get("password").unwrap()),
+        age: row.get("age").unwrap(),
+        address: row.get("address").unwrap(),
+        version: row.get("version").unwrap(),
+        // Stored in UTC.
+        deleted_at: row
+            .get::<Option<NaiveDateTime>, _>("deleted_at")
+            .unwrap()
+            .map(|v| v.and_utc()),
+    }
+}
+
+fn conflict(user: &User, expected_version: Option<u64>) -> Conflict {
+    Conflict {
+        id: user.id,
+        expected_version: expected_version.unwrap_or_default(),
+        actual_version: user.version,
     }
 }
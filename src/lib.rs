@@ -2,4 +2,6 @@ pub mod configuration;
 pub mod core;
 pub mod database;
 pub mod logger;
+pub mod metrics;
 pub mod server;
+pub mod telemetry;
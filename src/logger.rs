@@ -1,7 +1,35 @@
+pub mod context;
+
+use std::io::Write;
+
+use anyhow::{Context, Result};
 use backtrace::Backtrace;
 use log::{Level, Log, Metadata, Record};
+use regex::{Captures, Regex};
+
+const REDACTED: &str = "***";
+
+pub struct Logger {
+    redact_patterns: Vec<Regex>,
+}
 
-pub struct Logger;
+impl Logger {
+    pub fn new(redact_patterns: &[String]) -> Result<Self> {
+        let redact_patterns = redact_patterns
+            .iter()
+            .map(|v| Regex::new(v).context(format!("invalid redact pattern: {v}")))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self { redact_patterns })
+    }
+
+    fn scrub(&self, message: String) -> String {
+        self.redact_patterns
+            .iter()
+            .fold(message, |message, pattern| {
+                pattern.replace_all(&message, redact).into_owned()
+            })
+    }
+}
 
 impl Log for Logger {
     fn enabled(&self, metadata: &Metadata) -> bool {
@@ -9,25 +37,47 @@ impl Log for Logger {
     }
 
     fn log(&self, record: &Record) {
-        if self.enabled(record.metadata()) == false {
+        if !self.enabled(record.metadata()) {
             return;
         }
 
         let timestamp = chrono::Local::now().format("%Y-%m-%d %H:%M:%S%.3f %Z");
+        let message = match context::current_fields() {
+            Some(fields) => self.scrub(format!("[{fields}] {}", record.args())),
+            None => self.scrub(record.args().to_string()),
+        };
         if let Some(caller) = caller_name() {
-            println!(
-                "{}: {}: {}: {}",
-                timestamp,
-                record.level(),
-                caller,
-                record.args()
-            );
+            println!("{}: {}: {}: {}", timestamp, record.level(), caller, message);
         } else {
-            println!("{}: {}: {}", timestamp, record.level(), record.args());
+            println!("{}: {}: {}", timestamp, record.level(), message);
         }
     }
 
-    fn flush(&self) {}
+    fn flush(&self) {
+        std::io::stdout().flush().ok();
+    }
+}
+
+// Replaces the whole match, or only the captured groups if the pattern has any.
+fn redact(caps: &Captures) -> String {
+    let whole = caps.get(0).unwrap();
+    if caps.len() == 1 {
+        return String::from(REDACTED);
+    }
+
+    let mut result = String::new();
+    let mut last = whole.start();
+    for group in caps.iter().skip(1).flatten() {
+        if group.start() < last {
+            // Nested or overlapping group that has already been redacted.
+            continue;
+        }
+        result.push_str(&whole.as_str()[last - whole.start()..group.start() - whole.start()]);
+        result.push_str(REDACTED);
+        last = group.end();
+    }
+    result.push_str(&whole.as_str()[last - whole.start()..]);
+    result
 }
 
 #[inline(never)]
@@ -42,3 +92,61 @@ fn caller_name() -> Option<String> {
     let name = name.rsplit_once("::")?.0.to_string();
     Some(name)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scrub(patterns: &[&str], message: &str) -> String {
+        let patterns = patterns.iter().map(|v| v.to_string()).collect::<Vec<_>>();
+        Logger::new(&patterns).unwrap().scrub(message.to_string())
+    }
+
+    #[test]
+    fn redact_whole_match() {
+        assert_eq!(
+            scrub(&[r"\d{4}-\d{4}"], "card 1234-5678 and 8765-4321"),
+            "card *** and ***"
+        );
+    }
+
+    #[test]
+    fn redact_captured_groups_only() {
+        assert_eq!(
+            scrub(&[r"token=(\w+)&key=(\w+)"], "GET /?token=abc&key=def"),
+            "GET /?token=***&key=***"
+        );
+    }
+
+    #[test]
+    fn redact_nested_groups_once() {
+        assert_eq!(scrub(&[r"pw=((\w)\w*)"], "pw=secret!"), "pw=***!");
+    }
+
+    #[test]
+    fn redact_skips_unmatched_optional_groups() {
+        assert_eq!(
+            scrub(&[r"user=(\w+)(?: pw=(\w+))?"], "user=alice"),
+            "user=***"
+        );
+    }
+
+    #[test]
+    fn redact_applies_every_pattern() {
+        assert_eq!(
+            scrub(&["alice", r"pw=(\w+)"], "alice pw=secret"),
+            "*** pw=***"
+        );
+    }
+
+    #[test]
+    fn leave_unmatched_messages() {
+        assert_eq!(scrub(&["secret"], "nothing here"), "nothing here");
+        assert_eq!(scrub(&[], "nothing here"), "nothing here");
+    }
+
+    #[test]
+    fn reject_invalid_patterns() {
+        assert!(Logger::new(&["(".to_string()]).is_err());
+    }
+}
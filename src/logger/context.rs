@@ -0,0 +1,121 @@
+use std::fmt::{Debug, Write};
+use std::future::Future;
+
+use anyhow::{Context, Result};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::Subscriber;
+use tracing_subscriber::layer::{Context as LayerContext, SubscriberExt};
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::{Layer, Registry};
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+pub type BoxedLayer = Box<dyn Layer<Registry> + Send + Sync>;
+
+/// Installs the global `tracing` subscriber that keeps track of the fields of
+/// the active spans, so `Logger` can attach them to every log line. `layers`
+/// receive the spans as well, e.g., to export them.
+pub fn init(layers: Vec<BoxedLayer>) -> Result<()> {
+    // An empty `Vec` of layers disables every span, so leave it out entirely.
+    let layers = (!layers.is_empty()).then_some(layers);
+    let subscriber = Registry::default().with(layers).with(ContextLayer);
+    tracing::subscriber::set_global_default(subscriber)
+        .context("failed to set the global tracing subscriber")
+}
+
+/// Runs `f` with `request_id` attached to every log line it writes, even where
+/// no span is entered.
+pub async fn with_request_id<F: Future>(request_id: String, f: F) -> F::Output {
+    REQUEST_ID.scope(request_id, f).await
+}
+
+/// Returns the id of the request the current task is serving, if any.
+pub fn request_id() -> Option<String> {
+    REQUEST_ID.try_with(|v| v.clone()).ok()
+}
+
+/// Returns the request id and the fields of the current span and all of its
+/// parents, formatted as `key=value` pairs from the outermost span to the
+/// innermost one.
+pub fn current_fields() -> Option<String> {
+    let request_id = request_id();
+    let mut result = match &request_id {
+        Some(v) => format!("request_id={v}"),
+        None => String::new(),
+    };
+    tracing::Span::current().with_subscriber(|(id, dispatch)| {
+        let registry = dispatch.downcast_ref::<Registry>()?;
+        let span = registry.span(id)?;
+        for span in span.scope().from_root() {
+            let extensions = span.extensions();
+            let Some(fields) = extensions.get::<Fields>() else {
+                continue;
+            };
+            for (name, value) in &fields.0 {
+                // Hints for the OpenTelemetry exporter, not for humans.
+                if name.starts_with("otel.") {
+                    continue;
+                }
+                if *name == "request_id" && request_id.is_some() {
+                    continue;
+                }
+                if !result.is_empty() {
+                    result.push(' ');
+                }
+                write!(result, "{name}={value}").ok()?;
+            }
+        }
+        Some(())
+    });
+    Some(result).filter(|v| !v.is_empty())
+}
+
+struct ContextLayer;
+
+impl<S> Layer<S> for ContextLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: LayerContext<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut fields = Fields(Vec::new());
+        attrs.record(&mut fields);
+        span.extensions_mut().insert(fields);
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: LayerContext<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut extensions = span.extensions_mut();
+        if let Some(fields) = extensions.get_mut::<Fields>() {
+            values.record(fields);
+        }
+    }
+}
+
+struct Fields(Vec<(&'static str, String)>);
+
+impl Fields {
+    fn set(&mut self, name: &'static str, value: String) {
+        match self.0.iter_mut().find(|(k, _)| *k == name) {
+            Some((_, v)) => *v = value,
+            None => self.0.push((name, value)),
+        }
+    }
+}
+
+impl Visit for Fields {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.set(field.name(), value.to_string());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        self.set(field.name(), format!("{value:?}"));
+    }
+}
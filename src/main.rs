@@ -6,36 +6,57 @@ use rust_base::database::dummy::Dummy;
 use rust_base::database::mysql;
 use rust_base::logger;
 use rust_base::server::http;
+use rust_base::telemetry;
 
 use anyhow::{Context, Result};
+use tokio::signal::unix::{signal, SignalKind};
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let config = configuration::load().context("failed to load the configuration")?;
-    init_logger(config.log.level);
-    init_http_server(config).await?;
-    Ok(())
+    let exporter = match &config.telemetry {
+        Some(v) => Some(telemetry::Exporter::new(v).context("failed to initialize the telemetry")?),
+        None => None,
+    };
+    init_logger(&config.log, exporter.as_ref()).context("failed to initialize the logger")?;
+    let result = init_http_server(config).await;
+    if let Some(exporter) = exporter {
+        if let Err(err) = exporter.shutdown() {
+            log::error!("{err:?}");
+        }
+    }
+    log::logger().flush();
+    result
+}
+
+async fn shutdown_signal() {
+    let mut sigterm = signal(SignalKind::terminate()).expect("failed to listen for SIGTERM");
+    tokio::select! {
+        _ = sigterm.recv() => log::info!("received SIGTERM"),
+        _ = tokio::signal::ctrl_c() => log::info!("received SIGINT"),
+    }
 }
 
-fn init_logger(level: log::LevelFilter) {
-    log::set_boxed_logger(Box::new(logger::Logger)).unwrap();
-    log::set_max_level(level);
+fn init_logger(config: &configuration::Log, exporter: Option<&telemetry::Exporter>) -> Result<()> {
+    log::set_boxed_logger(Box::new(logger::Logger::new(&config.redact_patterns)?)).unwrap();
+    log::set_max_level(config.level);
+    logger::context::init(exporter.map(|v| v.layer()).into_iter().collect())
 }
 
-fn init_mysql(config: configuration::Database) -> impl DatabaseTransaction + Send + Sync {
+fn init_mysql(config: configuration::Database) -> Result<impl DatabaseTransaction + Send + Sync> {
     let c = database::Configuration {
         host: config.host,
         port: config.port,
         username: config.username,
-        password: config.password,
+        password: config.password.into_inner(),
         name: config.name,
     };
 
-    mysql::Client::new(c)
+    mysql::Client::new(c).context("failed to initialize the MySQL client")
 }
 
 fn init_dummy() -> impl DatabaseTransaction + Send + Sync {
-    Dummy {}
+    Dummy::default()
 }
 
 async fn init_http_server(config: configuration::Configuration) -> Result<()> {
@@ -43,19 +64,17 @@ async fn init_http_server(config: configuration::Configuration) -> Result<()> {
 
     if config.database.driver.to_lowercase() == "mysql" {
         http::serve(
-            Controller::new(init_mysql(config.database)),
-            config.http.port,
-            &config.http.tls_cert_file,
-            &config.http.tls_key_file,
+            Controller::new(init_mysql(config.database)?),
+            config.http,
+            shutdown_signal(),
         )
         .await
         .context("failed to serve HTTP service")?;
     } else {
         http::serve(
             Controller::new(init_dummy()),
-            config.http.port,
-            &config.http.tls_cert_file,
-            &config.http.tls_key_file,
+            config.http,
+            shutdown_signal(),
         )
         .await
         .context("failed to serve HTTP service")?;
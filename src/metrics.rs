@@ -0,0 +1,137 @@
+use std::sync::LazyLock;
+
+use anyhow::{Context, Result};
+use prometheus::core::Collector;
+use prometheus::{
+    HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry,
+    TextEncoder,
+};
+
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+pub struct Metrics {
+    registry: Registry,
+    pub http_requests: IntCounterVec,
+    pub http_request_duration: HistogramVec,
+    pub database_transactions: IntCounterVec,
+    pub deadlock_retries: IntCounter,
+    pub mysql_open_transactions: IntGauge,
+    pub tls_certificate_expiry: IntGaugeVec,
+    pub tls_reloads: IntCounterVec,
+    pub http_rate_limited: IntCounterVec,
+    pub http_rejected_requests: IntCounterVec,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let http_requests = IntCounterVec::new(
+            Opts::new("http_requests_total", "Number of HTTP requests."),
+            &["method", "route", "status"],
+        )
+        .unwrap();
+        let http_request_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "http_request_duration_seconds",
+                "Latency of HTTP requests in seconds.",
+            ),
+            &["method", "route", "status"],
+        )
+        .unwrap();
+        let database_transactions = IntCounterVec::new(
+            Opts::new(
+                "database_transactions_total",
+                "Number of database transaction operations.",
+            ),
+            &["operation"],
+        )
+        .unwrap();
+        let deadlock_retries = IntCounter::new(
+            "database_deadlock_retries_total",
+            "Number of transactions retried due to a deadlock.",
+        )
+        .unwrap();
+        let mysql_open_transactions = IntGauge::new(
+            "mysql_open_transactions",
+            "Number of MySQL transactions currently open.",
+        )
+        .unwrap();
+
+        let tls_certificate_expiry = IntGaugeVec::new(
+            Opts::new(
+                "tls_certificate_expiry_timestamp_seconds",
+                "Expiry of the served TLS certificate as a Unix timestamp.",
+            ),
+            &["cert_file"],
+        )
+        .unwrap();
+        let tls_reloads = IntCounterVec::new(
+            Opts::new(
+                "tls_reloads_total",
+                "Number of attempts to reload a changed TLS certificate.",
+            ),
+            &["result"],
+        )
+        .unwrap();
+
+        let http_rate_limited = IntCounterVec::new(
+            Opts::new(
+                "http_rate_limited_total",
+                "Number of HTTP requests rejected by the rate limiter.",
+            ),
+            &["route"],
+        )
+        .unwrap();
+        let http_rejected_requests = IntCounterVec::new(
+            Opts::new(
+                "http_rejected_requests_total",
+                "Number of HTTP requests rejected due to a timeout, an overload or a large body.",
+            ),
+            &["route", "reason"],
+        )
+        .unwrap();
+
+        let registry = Registry::new();
+        let collectors: [Box<dyn Collector>; 9] = [
+            Box::new(http_requests.clone()),
+            Box::new(http_request_duration.clone()),
+            Box::new(database_transactions.clone()),
+            Box::new(deadlock_retries.clone()),
+            Box::new(mysql_open_transactions.clone()),
+            Box::new(tls_certificate_expiry.clone()),
+            Box::new(tls_reloads.clone()),
+            Box::new(http_rate_limited.clone()),
+            Box::new(http_rejected_requests.clone()),
+        ];
+        for c in collectors {
+            registry.register(c).unwrap();
+        }
+
+        Self {
+            registry,
+            http_requests,
+            http_request_duration,
+            database_transactions,
+            deadlock_retries,
+            mysql_open_transactions,
+            tls_certificate_expiry,
+            tls_reloads,
+            http_rate_limited,
+            http_rejected_requests,
+        }
+    }
+
+    /// Registers an additional collector, e.g., one that reads the statistics
+    /// of a connection pool at scrape time.
+    pub fn register(&self, collector: Box<dyn Collector>) -> Result<()> {
+        self.registry
+            .register(collector)
+            .context("failed to register a metrics collector")
+    }
+
+    /// Encodes all the metrics in the Prometheus text format.
+    pub fn encode(&self) -> Result<String> {
+        TextEncoder::new()
+            .encode_to_string(&self.registry.gather())
+            .context("failed to encode the metrics")
+    }
+}
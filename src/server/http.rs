@@ -1,118 +1,470 @@
+mod access_log;
+mod compression;
+mod cors;
+mod cursor;
+mod error;
+mod etag;
+mod extract;
+mod health;
+mod idempotency;
+mod limits;
+mod listener;
+mod openapi;
+mod rate_limit;
+mod redirect;
+mod request_id;
+mod search;
+mod tls;
+mod unix;
+mod users;
+
+pub use openapi::ApiDoc;
+pub use request_id::RequestId;
+pub use tls::ClientIdentity;
+
+use crate::configuration::{self, RouteGroup};
 use crate::core::controller::Controller;
 use crate::core::controller::CreateUserParams as ControllerCreateUserParams;
 use crate::core::controller::GetUserParams as ControllerGetUserParams;
 use crate::core::entity::{DatabaseTransaction, User};
+use crate::core::secret::Secret;
+use crate::logger;
+use crate::metrics::METRICS;
+use error::ErrorBody;
 
-use std::net::SocketAddr;
+use std::collections::HashMap;
+use std::future::Future;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use anyhow::{Context, Result};
-use axum::extract::State;
-use axum::{routing::post, Json, Router};
-use axum_server::tls_rustls::RustlsConfig;
+use anyhow::{anyhow, Context, Result};
+use axum::extract::{DefaultBodyLimit, MatchedPath, Request, State};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use axum_server::Handle;
+use futures::future;
 use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+use tower_http::cors::CorsLayer;
+use tracing::field::Empty;
+use tracing::Instrument;
+use utoipa::ToSchema;
+use utoipa_swagger_ui::{Config, SwaggerUi};
 
 struct AppState<T> {
     controller: Controller<T>,
+    health: configuration::Health,
+    // None if TLS is disabled.
+    tls_status: Option<Arc<tls::Status>>,
+    openapi_ui: bool,
+    rate_limiter: Option<Arc<rate_limit::RateLimiter>>,
+    limiter: Arc<limits::Limiter>,
+    cors: HashMap<RouteGroup, CorsLayer>,
+    compression: Option<Arc<compression::Compression>>,
+    // Seconds to keep the responses for the idempotency keys.
+    idempotency_ttl: u64,
+    default_page_size: u32,
+    max_page_size: u32,
+    cursors: cursor::Cursors,
 }
 
-pub async fn serve<T>(
+/// Serves the HTTP API until `shutdown` resolves. Then, it stops accepting new
+/// connections, waits for the in-flight requests up to the grace period, and
+/// closes the database.
+pub async fn serve<T, F>(
     controller: Controller<T>,
-    port: u16,
-    tls_cert_file: &str,
-    tls_key_file: &str,
+    config: configuration::HTTP,
+    shutdown: F,
 ) -> Result<()>
+where
+    T: DatabaseTransaction + Send + Sync + 'static,
+    F: Future<Output = ()> + Send + 'static,
+{
+    if config.port.is_some() {
+        log::warn!("http.port, tls_cert_file and tls_key_file are deprecated, use http.listeners");
+    }
+    if config.listeners.is_empty() {
+        return Err(anyhow!("no HTTP listener is configured"));
+    }
+    let tls_status = Arc::new(tls::Status::default());
+    let mut listeners = Vec::new();
+    for v in config.listeners {
+        listeners.push(listener::Listener::new(v, tls_status.clone()).await?);
+    }
+
+    let access_log = match config.access_log {
+        Some(v) => Some(Arc::new(access_log::AccessLog::new(v)?)),
+        None => None,
+    };
+    let rate_limiter = match config.rate_limit {
+        Some(v) => Some(Arc::new(rate_limit::RateLimiter::new(
+            v,
+            Arc::new(rate_limit::MemoryStore::default()),
+        )?)),
+        None => None,
+    };
+    let pagination = config.pagination;
+    if pagination.max_page_size == 0 || pagination.default_page_size > pagination.max_page_size {
+        return Err(anyhow!(
+            "page sizes have to be 0 < default_page_size <= max_page_size"
+        ));
+    }
+    let mut cors = HashMap::new();
+    for (group, v) in &config.cors {
+        let layer = cors::layer(v).context(format!("invalid CORS policy of {group:?}"))?;
+        cors.insert(*group, layer);
+    }
+    let shared_state = Arc::new(AppState {
+        controller,
+        health: config.health,
+        tls_status: listeners.iter().any(|v| v.has_tls()).then_some(tls_status),
+        openapi_ui: config.openapi_ui,
+        rate_limiter,
+        limiter: Arc::new(limits::Limiter::new(config.limits)),
+        cors,
+        compression: config
+            .compression
+            .map(|v| Arc::new(compression::Compression::new(v))),
+        idempotency_ttl: config.idempotency.ttl,
+        default_page_size: pagination.default_page_size,
+        max_page_size: pagination.max_page_size,
+        cursors: cursor::Cursors::new(pagination.cursor_secret),
+    });
+
+    let (signal_tx, signal_rx) = watch::channel(false);
+    let grace_period = Duration::from_secs(config.grace_period);
+    let listener_shutdown = listener::Shutdown {
+        handle: Handle::new(),
+        signal: signal_rx.clone(),
+        grace_period,
+    };
+    if config.idempotency.purge_interval > 0 {
+        tokio::spawn(idempotency::purge(
+            shared_state.clone(),
+            Duration::from_secs(config.idempotency.purge_interval),
+            signal_rx.clone(),
+        ));
+    }
+    if config.retention.purge_interval > 0 {
+        tokio::spawn(users::purge_deleted(
+            shared_state.clone(),
+            Duration::from_secs(config.retention.deleted_users),
+            Duration::from_secs(config.retention.purge_interval),
+            signal_rx,
+        ));
+    }
+    tokio::spawn({
+        let handle = listener_shutdown.handle.clone();
+        async move {
+            shutdown.await;
+            log::info!(
+                "shutting down HTTP server: in-flight connections = {}",
+                handle.connection_count()
+            );
+            handle.graceful_shutdown(Some(grace_period));
+            signal_tx.send_replace(true);
+        }
+    });
+
+    let servers = listeners.into_iter().map(|v| {
+        let app = router(shared_state.clone(), v.routes(), access_log.clone());
+        v.serve(app, listener_shutdown.clone())
+    });
+    let result = future::try_join_all(servers).await.map(|_| ());
+
+    match tokio::time::timeout(grace_period, shared_state.controller.close()).await {
+        Ok(Ok(_)) => log::info!("closed the database"),
+        Ok(Err(err)) => log::error!("failed to close the database: {err:?}"),
+        Err(_) => log::error!("timed out while closing the database"),
+    }
+
+    result
+}
+
+fn router<T>(
+    state: Arc<AppState<T>>,
+    groups: &[RouteGroup],
+    access_log: Option<Arc<access_log::AccessLog>>,
+) -> Router
 where
     T: DatabaseTransaction + Send + Sync + 'static,
 {
-    let shared_state = Arc::new(AppState { controller });
-    let app = Router::new()
+    let mut app = Router::new();
+    for group in groups {
+        let mut routes = match group {
+            RouteGroup::Api => api_router(&state),
+            RouteGroup::Admin => Router::new()
+                .route("/healthz", get(health::healthz))
+                .route("/readyz", get(health::readyz)),
+            RouteGroup::Metrics => Router::new().route("/metrics", get(metrics)),
+        };
+        if let Some(cors) = state.cors.get(group) {
+            routes = routes.layer(cors.clone());
+        }
+        app = app.merge(routes);
+    }
+
+    app = app
+        .layer(middleware::from_fn(record_metrics))
+        .layer(middleware::from_fn(trace_request));
+    if let Some(access_log) = access_log {
+        app = app.layer(middleware::from_fn_with_state(
+            access_log,
+            access_log::record,
+        ));
+    }
+    app.layer(middleware::from_fn(request_id::propagate))
+        .with_state(state)
+}
+
+fn api_router<T>(state: &AppState<T>) -> Router<Arc<AppState<T>>>
+where
+    T: DatabaseTransaction + Send + Sync + 'static,
+{
+    let mut api = Router::new()
         .route("/api/v1/create_user", post(create_user))
         .route("/api/v1/get_user", post(get_user))
-        .with_state(shared_state);
-    let addr = SocketAddr::from(([0, 0, 0, 0], port));
-
-    let config = RustlsConfig::from_pem_file(tls_cert_file, tls_key_file)
-        .await
-        .context(format!(
-            "failed to load TLS cert and key files: {tls_cert_file}, {tls_key_file}"
-        ))?;
-
-    Ok(axum_server::bind_rustls(addr, config)
-        .serve(app.into_make_service())
-        .await
-        .context(format!("failed to bind HTTP server: {addr}"))?)
+        .route(
+            "/api/v2/users",
+            get(users::list_users).post(users::create_user),
+        )
+        .route("/api/v2/users/search", get(search::search_users))
+        .route(
+            "/api/v2/users/:id",
+            get(users::get_user)
+                .patch(users::update_user)
+                .delete(users::delete_user),
+        )
+        .route("/api/v2/users/:id/restore", post(users::restore_user))
+        .route("/openapi.json", get(openapi::spec))
+        .route_layer(middleware::from_fn_with_state(
+            state.limiter.clone(),
+            limits::enforce,
+        ))
+        // Replaced with the limits configured per route.
+        .route_layer(DefaultBodyLimit::disable());
+    if let Some(limiter) = &state.rate_limiter {
+        api = api.route_layer(middleware::from_fn_with_state(
+            limiter.clone(),
+            rate_limit::limit,
+        ));
+    }
+    if let Some(compression) = &state.compression {
+        // Outside the limits, so that they apply to the decompressed body.
+        api = api
+            .route_layer(compression.request_layer())
+            .route_layer(middleware::from_fn_with_state(
+                compression.clone(),
+                compression::negotiate,
+            ))
+            .layer(compression.response_layer());
+    }
+    if state.openapi_ui {
+        api = api.merge(SwaggerUi::new("/docs").config(Config::from("/openapi.json")));
+    }
+    api
 }
 
-#[derive(Deserialize)]
+async fn trace_request(request: Request, next: Next) -> Response {
+    let span = tracing::info_span!(
+        "http_request",
+        otel.kind = "server",
+        otel.name = format!("{} {}", request.method(), request.uri().path()),
+        request_id = Empty,
+        method = %request.method(),
+        path = request.uri().path(),
+        client = Empty,
+        status = Empty,
+    );
+    if let Some(RequestId(id)) = request.extensions().get::<RequestId>() {
+        span.record("request_id", id);
+    }
+    if let Some(Some(identity)) = request.extensions().get::<Option<ClientIdentity>>() {
+        span.record("client", &identity.subject);
+    }
+    let response = next.run(request).instrument(span.clone()).await;
+    span.record("status", response.status().as_u16());
+    response
+}
+
+async fn record_metrics(request: Request, next: Next) -> Response {
+    let method = request.method().to_string();
+    let route = match request.extensions().get::<MatchedPath>() {
+        Some(v) => v.as_str().to_string(),
+        None => String::from("unmatched"),
+    };
+    let started = Instant::now();
+    let response = next.run(request).await;
+
+    let status = response.status().as_u16().to_string();
+    let labels = [method.as_str(), route.as_str(), status.as_str()];
+    METRICS.http_requests.with_label_values(&labels).inc();
+    METRICS
+        .http_request_duration
+        .with_label_values(&labels)
+        .observe(started.elapsed().as_secs_f64());
+    response
+}
+
+async fn metrics() -> Response {
+    match METRICS.encode() {
+        Ok(v) => ([(header::CONTENT_TYPE, prometheus::TEXT_FORMAT)], v).into_response(),
+        Err(err) => {
+            log::error!("failed to export the metrics: {err:?}");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
 struct CreateUserParams {
     pub username: String,
-    pub password: String,
+    #[schema(value_type = String, format = Password)]
+    pub password: Secret<String>,
     pub age: u16,
     pub address: String,
 }
 
-impl Into<ControllerCreateUserParams> for CreateUserParams {
-    fn into(self) -> ControllerCreateUserParams {
-        ControllerCreateUserParams {
-            username: self.username,
-            password: self.password,
-            age: self.age,
-            address: self.address,
+impl From<CreateUserParams> for ControllerCreateUserParams {
+    fn from(params: CreateUserParams) -> Self {
+        Self {
+            username: params.username,
+            password: params.password,
+            age: params.age,
+            address: params.address,
         }
     }
 }
 
-#[derive(Serialize)]
+/// `code` is 200 on success and 500 on failure.
+#[derive(Serialize, ToSchema)]
 struct CreateUserResponse {
     code: u16,
     user: Option<User>,
+    /// Set on failure to tie it to the server logs.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
 }
 
+#[utoipa::path(
+    post,
+    path = "/api/v1/create_user",
+    tag = "v1",
+    request_body = CreateUserParams,
+    params((
+        "idempotency-key" = Option<String>,
+        Header,
+        description = "Replays the first response to the retries with the same key",
+    )),
+    responses(
+        (status = 200, description = "Result", body = CreateUserResponse),
+        (status = 400, description = "Invalid idempotency key", body = ErrorBody),
+        (
+            status = 422,
+            description = "Idempotency key used for another request",
+            body = ErrorBody,
+        ),
+    ),
+)]
 async fn create_user<T>(
     State(state): State<Arc<AppState<T>>>,
+    headers: HeaderMap,
     Json(payload): Json<CreateUserParams>,
-) -> Json<CreateUserResponse>
+) -> Response
 where
     T: DatabaseTransaction + Send + Sync,
 {
     log::debug!("create_user invoked");
+    let failed = |err: anyhow::Error| {
+        log::error!("failed to create a user: {err:?}");
+        let response = CreateUserResponse {
+            code: 500,
+            user: None,
+            request_id: logger::context::request_id(),
+        };
+        Json(response).into_response()
+    };
+
+    let operation = "POST /api/v1/create_user";
+    match idempotency::key(&headers, operation, &payload, state.idempotency_ttl) {
+        Ok(Some(key)) => {
+            let respond = {
+                let key = key.clone();
+                move |user: &User| {
+                    // The stored body outlives the request, so the password
+                    // must not get into it whatever the serialization does.
+                    let user = User {
+                        password: Secret::default(),
+                        ..user.clone()
+                    };
+                    let response = CreateUserResponse {
+                        code: 200,
+                        user: Some(user),
+                        request_id: None,
+                    };
+                    idempotency::store(&key, StatusCode::OK, &[], &response)
+                }
+            };
+            return match state
+                .controller
+                .create_user_once(payload, key, respond)
+                .await
+            {
+                Ok(v) => idempotency::respond(v),
+                Err(err) => failed(err),
+            };
+        }
+        Ok(None) => {}
+        Err(err) => return err.into_response(),
+    }
+
     return match state.controller.create_user(payload).await {
         Ok(user) => {
             let response = CreateUserResponse {
                 code: 200,
                 user: Some(user),
+                request_id: None,
             };
-            Json(response)
-        }
-        Err(err) => {
-            log::error!("failed to create a user: {err:?}");
-            let response = CreateUserResponse {
-                code: 500,
-                user: None,
-            };
-            Json(response)
+            Json(response).into_response()
         }
+        Err(err) => failed(err),
     };
 }
 
-#[derive(Deserialize)]
+#[derive(Debug, Deserialize, ToSchema)]
 struct GetUserParams {
     pub id: u64,
 }
 
-impl Into<ControllerGetUserParams> for GetUserParams {
-    fn into(self) -> ControllerGetUserParams {
-        ControllerGetUserParams { id: self.id }
+impl From<GetUserParams> for ControllerGetUserParams {
+    fn from(params: GetUserParams) -> Self {
+        Self {
+            id: params.id,
+            include_deleted: false,
+        }
     }
 }
 
-#[derive(Serialize)]
+/// `code` is 200 on success, 300 if the user does not exist and 500 on failure.
+#[derive(Serialize, ToSchema)]
 struct GetUserResponse {
     code: u16,
     user: Option<User>,
+    /// Set on failure to tie it to the server logs.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
 }
 
+#[utoipa::path(
+    post,
+    path = "/api/v1/get_user",
+    tag = "v1",
+    request_body = GetUserParams,
+    responses((status = 200, description = "Result", body = GetUserResponse)),
+)]
 async fn get_user<T>(
     State(state): State<Arc<AppState<T>>>,
     Json(payload): Json<GetUserParams>,
@@ -127,9 +479,14 @@ where
                 Json(GetUserResponse {
                     code: 300,
                     user: None,
+                    request_id: None,
                 })
             } else {
-                Json(GetUserResponse { code: 200, user })
+                Json(GetUserResponse {
+                    code: 200,
+                    user,
+                    request_id: None,
+                })
             }
         }
         Err(err) => {
@@ -137,6 +494,7 @@ where
             let response = GetUserResponse {
                 code: 500,
                 user: None,
+                request_id: logger::context::request_id(),
             };
             Json(response)
         }
@@ -0,0 +1,197 @@
+use super::{ClientIdentity, RequestId};
+use crate::configuration::{self, AccessLogFormat};
+
+use std::collections::HashSet;
+use std::fs::OpenOptions;
+use std::io::{self, LineWriter, Write};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::time::Instant;
+
+use anyhow::{Context, Result};
+use axum::body::HttpBody;
+use axum::extract::{ConnectInfo, Request, State};
+use axum::http::{header, HeaderMap, HeaderName};
+use axum::middleware::Next;
+use axum::response::Response;
+use chrono::{DateTime, Local};
+use serde::Serialize;
+
+// Lines waiting to be written. More are dropped rather than slowing down the
+// requests if the sink cannot keep up.
+const QUEUE_SIZE: usize = 4096;
+
+/// Writes a line per request to a sink separate from the application log.
+pub(super) struct AccessLog {
+    format: AccessLogFormat,
+    sample_rate: f64,
+    exclude_paths: HashSet<String>,
+    counter: AtomicU64,
+    sender: SyncSender<String>,
+}
+
+impl AccessLog {
+    pub(super) fn new(config: configuration::AccessLog) -> Result<Self> {
+        let mut sink: Box<dyn Write + Send> = match config.path.as_str() {
+            "stdout" => Box::new(io::stdout()),
+            "stderr" => Box::new(io::stderr()),
+            path => Box::new(LineWriter::new(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .context(format!("failed to open the access log: {path}"))?,
+            )),
+        };
+        let (sender, receiver) = mpsc::sync_channel::<String>(QUEUE_SIZE);
+        std::thread::spawn(move || {
+            for line in receiver {
+                if let Err(err) = writeln!(sink, "{line}") {
+                    log::error!("failed to write the access log: {err:?}");
+                }
+            }
+        });
+
+        Ok(Self {
+            format: config.format,
+            sample_rate: config.sample_rate.clamp(0.0, 1.0),
+            exclude_paths: config.exclude_paths.into_iter().collect(),
+            counter: AtomicU64::new(0),
+            sender,
+        })
+    }
+
+    // Picks every 1/sample_rate-th request, so the rate holds without randomness.
+    fn sampled(&self) -> bool {
+        let n = self.counter.fetch_add(1, Ordering::Relaxed) as f64;
+        (n * self.sample_rate).floor() != ((n + 1.0) * self.sample_rate).floor()
+    }
+
+    fn write(&self, line: String) {
+        match self.sender.try_send(line) {
+            Ok(_) => {}
+            Err(TrySendError::Full(_)) => log::warn!("access log queue is full, dropping a line"),
+            Err(TrySendError::Disconnected(_)) => log::error!("access log writer has stopped"),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Entry<'a> {
+    timestamp: String,
+    client: Option<String>,
+    user: Option<&'a str>,
+    method: &'a str,
+    path: &'a str,
+    protocol: String,
+    status: u16,
+    bytes: Option<u64>,
+    latency_ms: f64,
+    referer: Option<&'a str>,
+    user_agent: Option<&'a str>,
+    request_id: Option<&'a str>,
+}
+
+pub(super) async fn record(
+    State(access_log): State<Arc<AccessLog>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if access_log.exclude_paths.contains(request.uri().path()) {
+        return next.run(request).await;
+    }
+
+    let started = Instant::now();
+    let timestamp = Local::now();
+    let method = request.method().to_string();
+    let path = match request.uri().path_and_query() {
+        Some(v) => v.to_string(),
+        None => request.uri().path().to_string(),
+    };
+    let protocol = format!("{:?}", request.version());
+    let headers = request.headers().clone();
+    let extensions = request.extensions();
+    let client = extensions
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|v| v.0.ip().to_string());
+    let user = match extensions.get::<Option<ClientIdentity>>() {
+        Some(Some(identity)) => Some(identity.subject.clone()),
+        _ => None,
+    };
+    let request_id = extensions.get::<RequestId>().map(|v| v.0.clone());
+
+    let response = next.run(request).await;
+    // Sample after serving the request, so that no server error is missed.
+    if !access_log.sampled() && !response.status().is_server_error() {
+        return response;
+    }
+
+    let entry = Entry {
+        timestamp: format_timestamp(&timestamp, access_log.format),
+        client,
+        user: user.as_deref(),
+        method: &method,
+        path: &path,
+        protocol,
+        status: response.status().as_u16(),
+        bytes: response.body().size_hint().exact(),
+        latency_ms: started.elapsed().as_secs_f64() * 1000.0,
+        referer: header_str(&headers, &header::REFERER),
+        user_agent: header_str(&headers, &header::USER_AGENT),
+        request_id: request_id.as_deref(),
+    };
+    access_log.write(format_entry(&entry, access_log.format));
+    response
+}
+
+fn format_timestamp(timestamp: &DateTime<Local>, format: AccessLogFormat) -> String {
+    match format {
+        AccessLogFormat::Json => timestamp.to_rfc3339(),
+        _ => timestamp.format("%d/%b/%Y:%H:%M:%S %z").to_string(),
+    }
+}
+
+fn format_entry(entry: &Entry, format: AccessLogFormat) -> String {
+    let common = || {
+        format!(
+            "{} - {} [{}] \"{} {} {}\" {} {}",
+            entry.client.as_deref().unwrap_or("-"),
+            entry.user.map(quote).unwrap_or_else(|| String::from("-")),
+            entry.timestamp,
+            entry.method,
+            entry.path,
+            entry.protocol,
+            entry.status,
+            entry
+                .bytes
+                .map(|v| v.to_string())
+                .unwrap_or_else(|| String::from("-")),
+        )
+    };
+
+    match format {
+        AccessLogFormat::Common => common(),
+        AccessLogFormat::Combined => format!(
+            "{} \"{}\" \"{}\"",
+            common(),
+            escape(entry.referer.unwrap_or("-")),
+            escape(entry.user_agent.unwrap_or("-")),
+        ),
+        AccessLogFormat::Json => serde_json::to_string(entry).unwrap_or_default(),
+    }
+}
+
+fn header_str<'a>(headers: &'a HeaderMap, name: &HeaderName) -> Option<&'a str> {
+    headers.get(name).and_then(|v| v.to_str().ok())
+}
+
+// The subject of a client certificate has spaces, which would split the field.
+fn quote(v: &str) -> String {
+    format!("\"{}\"", escape(v))
+}
+
+fn escape(v: &str) -> String {
+    v.replace('\\', "\\\\").replace('"', "\\\"")
+}
@@ -0,0 +1,143 @@
+use super::error::Error;
+use crate::configuration::{self, CompressionAlgorithm};
+
+use std::sync::Arc;
+
+use axum::body::HttpBody;
+use axum::extract::{MatchedPath, Request, State};
+use axum::http::{header, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use tower_http::compression::predicate::{NotForContentType, Predicate, SizeAbove};
+use tower_http::compression::CompressionLayer;
+use tower_http::decompression::RequestDecompressionLayer;
+
+struct RouteCompression {
+    path: String,
+    method: Option<String>,
+    enabled: Option<bool>,
+    min_size: Option<u16>,
+    decompress_requests: Option<bool>,
+}
+
+/// Resolves the compression settings of the routes. `negotiate` attaches them to
+/// the responses, which `RoutePredicate` reads when the response goes through the
+/// compression layer.
+pub(super) struct Compression {
+    algorithms: Vec<CompressionAlgorithm>,
+    min_size: u16,
+    decompress_requests: bool,
+    routes: Vec<RouteCompression>,
+}
+
+impl Compression {
+    pub(super) fn new(config: configuration::Compression) -> Self {
+        let routes = config
+            .routes
+            .into_iter()
+            .map(|v| RouteCompression {
+                path: v.path,
+                method: v.method.map(|v| v.to_uppercase()),
+                enabled: v.enabled,
+                min_size: v.min_size,
+                decompress_requests: v.decompress_requests,
+            })
+            .collect();
+
+        Self {
+            algorithms: config.algorithms,
+            min_size: config.min_size,
+            decompress_requests: config.decompress_requests,
+            routes,
+        }
+    }
+
+    fn route(&self, method: &str, route: &str) -> Option<&RouteCompression> {
+        self.routes
+            .iter()
+            .find(|v| v.path == route && v.method.as_deref().is_none_or(|v| v == method))
+    }
+
+    fn enabled(&self, algorithm: CompressionAlgorithm) -> bool {
+        self.algorithms.contains(&algorithm)
+    }
+
+    pub(super) fn response_layer(&self) -> CompressionLayer<RoutePredicate> {
+        CompressionLayer::new()
+            .gzip(self.enabled(CompressionAlgorithm::Gzip))
+            .br(self.enabled(CompressionAlgorithm::Br))
+            .zstd(self.enabled(CompressionAlgorithm::Zstd))
+            .no_deflate()
+            .compress_when(RoutePredicate)
+    }
+
+    pub(super) fn request_layer(&self) -> RequestDecompressionLayer {
+        RequestDecompressionLayer::new()
+            .gzip(self.enabled(CompressionAlgorithm::Gzip))
+            .br(self.enabled(CompressionAlgorithm::Br))
+            .zstd(self.enabled(CompressionAlgorithm::Zstd))
+            .no_deflate()
+    }
+}
+
+// Set on the responses of the routes to compress.
+#[derive(Debug, Clone, Copy)]
+struct MinSize(u16);
+
+#[derive(Debug, Clone, Copy)]
+pub(super) struct RoutePredicate;
+
+impl Predicate for RoutePredicate {
+    fn should_compress<B>(&self, response: &axum::http::Response<B>) -> bool
+    where
+        B: HttpBody,
+    {
+        let Some(MinSize(min_size)) = response.extensions().get::<MinSize>() else {
+            return false;
+        };
+        // Images and event streams are either compressed already or have to be
+        // flushed as they are written.
+        SizeAbove::new(*min_size)
+            .and(NotForContentType::GRPC)
+            .and(NotForContentType::IMAGES)
+            .and(NotForContentType::SSE)
+            .should_compress(response)
+    }
+}
+
+pub(super) async fn negotiate(
+    State(compression): State<Arc<Compression>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let method = request.method().to_string();
+    let route = match request.extensions().get::<MatchedPath>() {
+        Some(v) => v.as_str().to_string(),
+        None => return next.run(request).await,
+    };
+    let settings = compression.route(&method, &route);
+
+    let decompress_requests = settings
+        .and_then(|v| v.decompress_requests)
+        .unwrap_or(compression.decompress_requests);
+    let encoded = request
+        .headers()
+        .get(header::CONTENT_ENCODING)
+        .is_some_and(|v| v.as_bytes() != b"identity");
+    if encoded && !decompress_requests {
+        return Error::new(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "compressed request body is not accepted",
+        )
+        .into_response();
+    }
+
+    let mut response = next.run(request).await;
+    if settings.and_then(|v| v.enabled).unwrap_or(true) {
+        let min_size = settings
+            .and_then(|v| v.min_size)
+            .unwrap_or(compression.min_size);
+        response.extensions_mut().insert(MinSize(min_size));
+    }
+    response
+}
@@ -0,0 +1,148 @@
+use crate::configuration;
+
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use axum::http::{HeaderName, HeaderValue, Method};
+use regex::Regex;
+use tower_http::cors::{AllowHeaders, AllowMethods, AllowOrigin, CorsLayer, ExposeHeaders};
+
+const WILDCARD: &str = "*";
+
+pub(super) fn layer(config: &configuration::Cors) -> Result<CorsLayer> {
+    // Any site could make credentialed requests on behalf of the users.
+    if config.allow_credentials && is_wildcard(&config.allowed_origins) {
+        return Err(anyhow!(
+            "CORS allowed origins cannot be {WILDCARD} with credentials"
+        ));
+    }
+    let origins = config
+        .allowed_origins
+        .iter()
+        .map(|v| origin_pattern(v))
+        .collect::<Result<Vec<_>>>()?;
+    // Echoes the origin of the request back if it is allowed, which works with
+    // credentials unlike `Access-Control-Allow-Origin: *`.
+    let allow_origin = AllowOrigin::predicate(move |origin: &HeaderValue, _| {
+        let origin = origin.to_str().unwrap_or_default();
+        origins.iter().any(|v| v.is_match(origin))
+    });
+
+    let allow_methods = if is_wildcard(&config.allowed_methods) {
+        AllowMethods::mirror_request()
+    } else {
+        let methods = config
+            .allowed_methods
+            .iter()
+            .map(|v| {
+                Method::from_bytes(v.to_uppercase().as_bytes())
+                    .context(format!("invalid CORS method: {v}"))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        AllowMethods::list(methods)
+    };
+    let allow_headers = if is_wildcard(&config.allowed_headers) {
+        AllowHeaders::mirror_request()
+    } else {
+        AllowHeaders::list(header_names(&config.allowed_headers)?)
+    };
+    if config.allow_credentials && is_wildcard(&config.exposed_headers) {
+        return Err(anyhow!(
+            "CORS exposed headers cannot be {WILDCARD} with credentials"
+        ));
+    }
+    let expose_headers = if is_wildcard(&config.exposed_headers) {
+        ExposeHeaders::any()
+    } else {
+        ExposeHeaders::list(header_names(&config.exposed_headers)?)
+    };
+
+    let mut layer = CorsLayer::new()
+        .allow_origin(allow_origin)
+        .allow_methods(allow_methods)
+        .allow_headers(allow_headers)
+        .expose_headers(expose_headers)
+        .allow_credentials(config.allow_credentials);
+    if let Some(max_age) = config.max_age {
+        layer = layer.max_age(Duration::from_secs(max_age));
+    }
+    Ok(layer)
+}
+
+fn is_wildcard(values: &[String]) -> bool {
+    values.iter().any(|v| v == WILDCARD)
+}
+
+fn header_names(values: &[String]) -> Result<Vec<HeaderName>> {
+    values
+        .iter()
+        .map(|v| HeaderName::try_from(v.as_str()).context(format!("invalid CORS header: {v}")))
+        .collect()
+}
+
+// `*` alone matches any origin. Otherwise, it stands for host name characters
+// only, so that it cannot match past the host, e.g., into the port.
+fn origin_pattern(origin: &str) -> Result<Regex> {
+    if origin == WILDCARD {
+        return Ok(Regex::new(".*").unwrap());
+    }
+    let pattern = origin
+        .split(WILDCARD)
+        .map(regex::escape)
+        .collect::<Vec<_>>()
+        .join("[A-Za-z0-9.-]+");
+    Regex::new(&format!("^{pattern}$")).context(format!("invalid CORS origin: {origin}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exact_origin() {
+        let pattern = origin_pattern("https://example.com").unwrap();
+        assert!(pattern.is_match("https://example.com"));
+        assert!(!pattern.is_match("https://example.com.evil.com"));
+        assert!(!pattern.is_match("https://example.com:8080"));
+        assert!(!pattern.is_match("http://example.com"));
+        // The dot is not a regex wildcard.
+        assert!(!pattern.is_match("https://exampleXcom"));
+    }
+
+    #[test]
+    fn wildcard_subdomain() {
+        let pattern = origin_pattern("https://*.example.com").unwrap();
+        assert!(pattern.is_match("https://api.example.com"));
+        assert!(pattern.is_match("https://a.b.example.com"));
+        assert!(!pattern.is_match("https://example.com"));
+        assert!(!pattern.is_match("https://evil.com/.example.com"));
+        assert!(!pattern.is_match("https://api.example.com.evil.com"));
+    }
+
+    #[test]
+    fn wildcard_does_not_match_port() {
+        let pattern = origin_pattern("http://localhost*").unwrap();
+        assert!(pattern.is_match("http://localhost2"));
+        assert!(!pattern.is_match("http://localhost:8080"));
+    }
+
+    #[test]
+    fn wildcard_alone_matches_any_origin() {
+        let pattern = origin_pattern("*").unwrap();
+        assert!(pattern.is_match("https://example.com"));
+        assert!(pattern.is_match("null"));
+    }
+
+    #[test]
+    fn wildcard_origin_with_credentials() {
+        let config = configuration::Cors {
+            allowed_origins: vec![String::from(WILDCARD)],
+            allowed_methods: vec![String::from("GET")],
+            allowed_headers: Vec::new(),
+            exposed_headers: Vec::new(),
+            allow_credentials: true,
+            max_age: None,
+        };
+        assert!(layer(&config).is_err());
+    }
+}
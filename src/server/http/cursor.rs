@@ -0,0 +1,164 @@
+use super::error::Error;
+use crate::core::entity::{UserFilter, UserKey, UserSort};
+use crate::core::secret::Secret;
+
+use axum::http::StatusCode;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use sha2::Sha256;
+use uuid::Uuid;
+
+type HmacSha256 = Hmac<Sha256>;
+
+const SEPARATOR: char = '.';
+
+/// Encodes the position of the last user of a page into the cursor of the next
+/// one. It is signed along with the sort order and the filter, so that it is
+/// rejected if forged or used with another query.
+pub(super) struct Cursors {
+    secret: Vec<u8>,
+}
+
+#[derive(Serialize)]
+struct Query<'a> {
+    sort: &'a UserSort,
+    filter: &'a UserFilter,
+}
+
+impl Cursors {
+    pub(super) fn new(secret: Option<Secret<String>>) -> Self {
+        let secret = match secret {
+            Some(v) => v.into_inner().into_bytes(),
+            None => {
+                log::info!("signing the cursors with a random key");
+                [Uuid::new_v4(), Uuid::new_v4()]
+                    .iter()
+                    .flat_map(|v| v.into_bytes())
+                    .collect()
+            }
+        };
+        Self { secret }
+    }
+
+    pub(super) fn encode(&self, key: &UserKey, sort: &UserSort, filter: &UserFilter) -> String {
+        // Serializing these types cannot fail.
+        let payload = serde_json::to_vec(key).unwrap_or_default();
+        let signature = self.sign(&payload, sort, filter);
+        format!(
+            "{}{SEPARATOR}{}",
+            URL_SAFE_NO_PAD.encode(payload),
+            URL_SAFE_NO_PAD.encode(signature)
+        )
+    }
+
+    pub(super) fn decode(
+        &self,
+        cursor: &str,
+        sort: &UserSort,
+        filter: &UserFilter,
+    ) -> Result<UserKey, Error> {
+        let invalid = || Error::new(StatusCode::BAD_REQUEST, "invalid cursor");
+        let (payload, signature) = cursor.split_once(SEPARATOR).ok_or_else(invalid)?;
+        let payload = URL_SAFE_NO_PAD.decode(payload).map_err(|_| invalid())?;
+        let signature = URL_SAFE_NO_PAD.decode(signature).map_err(|_| invalid())?;
+        self.mac(&payload, sort, filter)
+            .verify_slice(&signature)
+            .map_err(|_| invalid())?;
+        serde_json::from_slice(&payload).map_err(|_| invalid())
+    }
+
+    fn sign(&self, payload: &[u8], sort: &UserSort, filter: &UserFilter) -> Vec<u8> {
+        self.mac(payload, sort, filter)
+            .finalize()
+            .into_bytes()
+            .to_vec()
+    }
+
+    fn mac(&self, payload: &[u8], sort: &UserSort, filter: &UserFilter) -> HmacSha256 {
+        let query = serde_json::to_vec(&Query { sort, filter }).unwrap_or_default();
+        // HMAC accepts a key of any length.
+        let mut mac = HmacSha256::new_from_slice(&self.secret).unwrap();
+        mac.update(&(payload.len() as u64).to_be_bytes());
+        mac.update(payload);
+        mac.update(&query);
+        mac
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::core::entity::UserSortField;
+
+    const SORT: UserSort = UserSort {
+        field: UserSortField::Username,
+        descending: false,
+    };
+
+    fn cursors(secret: &str) -> Cursors {
+        Cursors::new(Some(Secret::new(secret.to_string())))
+    }
+
+    fn key() -> UserKey {
+        UserKey::Username(String::from("alice"), 7)
+    }
+
+    #[test]
+    fn round_trip() {
+        let cursors = cursors("secret");
+        let filter = UserFilter::default();
+        let cursor = cursors.encode(&key(), &SORT, &filter);
+        assert_eq!(cursors.decode(&cursor, &SORT, &filter).ok(), Some(key()));
+    }
+
+    #[test]
+    fn tampered_payload_is_rejected() {
+        let cursors = cursors("secret");
+        let filter = UserFilter::default();
+        let cursor = cursors.encode(&key(), &SORT, &filter);
+        let (_, signature) = cursor.split_once(SEPARATOR).unwrap();
+        let payload = URL_SAFE_NO_PAD.encode(br#"{"Username":["bob",7]}"#);
+        let forged = format!("{payload}{SEPARATOR}{signature}");
+        assert!(cursors.decode(&forged, &SORT, &filter).is_err());
+    }
+
+    #[test]
+    fn tampered_signature_is_rejected() {
+        let cursors = cursors("secret");
+        let filter = UserFilter::default();
+        let cursor = cursors.encode(&key(), &SORT, &filter);
+        let (payload, signature) = cursor.split_once(SEPARATOR).unwrap();
+        let first = if signature.starts_with('A') { 'B' } else { 'A' };
+        let cursor = format!("{payload}{SEPARATOR}{first}{}", &signature[1..]);
+        assert!(cursors.decode(&cursor, &SORT, &filter).is_err());
+        assert!(cursors.decode("garbage", &SORT, &filter).is_err());
+    }
+
+    #[test]
+    fn cursor_of_another_query_is_rejected() {
+        let cursors = cursors("secret");
+        let filter = UserFilter::default();
+        let cursor = cursors.encode(&key(), &SORT, &filter);
+
+        let descending = UserSort {
+            descending: true,
+            ..SORT
+        };
+        assert!(cursors.decode(&cursor, &descending, &filter).is_err());
+        let filtered = UserFilter {
+            min_age: Some(18),
+            ..Default::default()
+        };
+        assert!(cursors.decode(&cursor, &SORT, &filtered).is_err());
+    }
+
+    #[test]
+    fn cursor_signed_with_another_secret_is_rejected() {
+        let filter = UserFilter::default();
+        let cursor = cursors("secret").encode(&key(), &SORT, &filter);
+        assert!(cursors("other").decode(&cursor, &SORT, &filter).is_err());
+    }
+}
@@ -0,0 +1,48 @@
+use crate::logger;
+
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde::Serialize;
+use utoipa::ToSchema;
+
+/// Error response of the v2 API and the middlewares.
+pub(super) struct Error {
+    status: StatusCode,
+    message: String,
+}
+
+impl Error {
+    pub(super) fn new(status: StatusCode, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            message: message.into(),
+        }
+    }
+
+    pub(super) fn not_found(id: u64) -> Self {
+        Self::new(StatusCode::NOT_FOUND, format!("user not found: {id}"))
+    }
+
+    pub(super) fn internal(action: &str, err: anyhow::Error) -> Self {
+        log::error!("failed to {action}: {err:?}");
+        Self::new(StatusCode::INTERNAL_SERVER_ERROR, "internal server error")
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub(super) struct ErrorBody {
+    error: String,
+    /// Ties the error to the server logs.
+    request_id: Option<String>,
+}
+
+impl IntoResponse for Error {
+    fn into_response(self) -> Response {
+        let body = ErrorBody {
+            error: self.message,
+            request_id: logger::context::request_id(),
+        };
+        (self.status, Json(body)).into_response()
+    }
+}
@@ -0,0 +1,115 @@
+use super::error::Error;
+
+use axum::http::{header, HeaderMap, HeaderName, StatusCode};
+
+const WILDCARD: &str = "*";
+const WEAK_PREFIX: &str = "W/";
+
+/// Entity tag of a version of a user. It is strong, as `If-Match` only accepts
+/// strong ones.
+pub(super) fn etag(version: u64) -> String {
+    format!("\"{version}\"")
+}
+
+/// Version required by `If-Match`. None if the header is missing or `*`.
+pub(super) fn if_match(headers: &HeaderMap) -> Result<Option<u64>, Error> {
+    let Some(value) = header_str(headers, &header::IF_MATCH)? else {
+        return Ok(None);
+    };
+    if value.trim() == WILDCARD {
+        return Ok(None);
+    }
+
+    let tags = value.split(',').map(str::trim).collect::<Vec<_>>();
+    if tags.len() > 1 {
+        return Err(Error::new(
+            StatusCode::BAD_REQUEST,
+            "If-Match with more than one entity tag is not supported",
+        ));
+    }
+    // A weak or a foreign tag never matches, which the version 0 stands for.
+    Ok(Some(version(tags[0]).unwrap_or(0)))
+}
+
+/// Whether the client has the version already according to `If-None-Match`.
+pub(super) fn if_none_match(headers: &HeaderMap, version: u64) -> bool {
+    let Ok(Some(value)) = header_str(headers, &header::IF_NONE_MATCH) else {
+        return false;
+    };
+    // Weak comparison, i.e., W/ is ignored.
+    value.split(',').map(str::trim).any(|v| {
+        v == WILDCARD || self::version(v.strip_prefix(WEAK_PREFIX).unwrap_or(v)) == Some(version)
+    })
+}
+
+fn version(tag: &str) -> Option<u64> {
+    tag.strip_prefix('"')?.strip_suffix('"')?.parse().ok()
+}
+
+fn header_str<'a>(headers: &'a HeaderMap, name: &HeaderName) -> Result<Option<&'a str>, Error> {
+    match headers.get(name) {
+        Some(v) => v
+            .to_str()
+            .map(Some)
+            .map_err(|_| Error::new(StatusCode::BAD_REQUEST, format!("invalid {name} header"))),
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use axum::http::HeaderValue;
+
+    fn headers(name: HeaderName, value: &[u8]) -> HeaderMap {
+        HeaderMap::from_iter([(name, HeaderValue::from_bytes(value).unwrap())])
+    }
+
+    // Some(None) stands for no precondition, None for an error.
+    fn parse_if_match(value: &str) -> Option<Option<u64>> {
+        if_match(&headers(header::IF_MATCH, value.as_bytes())).ok()
+    }
+
+    #[test]
+    fn if_match_without_precondition() {
+        assert_eq!(if_match(&HeaderMap::new()).ok(), Some(None));
+        assert_eq!(parse_if_match("*"), Some(None));
+        assert_eq!(parse_if_match(" * "), Some(None));
+    }
+
+    #[test]
+    fn if_match_strong_tag() {
+        assert_eq!(parse_if_match("\"3\""), Some(Some(3)));
+        assert_eq!(parse_if_match(&etag(42)), Some(Some(42)));
+    }
+
+    #[test]
+    fn if_match_never_matches_weak_or_foreign_tag() {
+        assert_eq!(parse_if_match("W/\"3\""), Some(Some(0)));
+        assert_eq!(parse_if_match("3"), Some(Some(0)));
+        assert_eq!(parse_if_match("\"abc\""), Some(Some(0)));
+    }
+
+    #[test]
+    fn if_match_rejects_several_tags() {
+        assert_eq!(parse_if_match("\"1\", \"2\""), None);
+    }
+
+    #[test]
+    fn if_match_rejects_invalid_header() {
+        assert!(if_match(&headers(header::IF_MATCH, b"\"\xff\"")).is_err());
+    }
+
+    #[test]
+    fn if_none_match_compares_weakly() {
+        let matches =
+            |value: &str| if_none_match(&headers(header::IF_NONE_MATCH, value.as_bytes()), 3);
+        assert!(matches("\"3\""));
+        assert!(matches("W/\"3\""));
+        assert!(matches("\"1\", W/\"3\""));
+        assert!(matches("*"));
+        assert!(!matches("\"4\""));
+        assert!(!if_none_match(&HeaderMap::new(), 3));
+    }
+}
@@ -0,0 +1,63 @@
+use super::error::Error;
+
+use axum::async_trait;
+use axum::extract::{self, FromRequest, FromRequestParts, Request};
+use axum::http::request::Parts;
+use serde::de::DeserializeOwned;
+
+// Extractors of the v2 API, which reject the invalid requests with `Error`
+// like the handlers do instead of the plain text of axum.
+
+pub(super) struct Path<T>(pub(super) T);
+
+#[async_trait]
+impl<T, S> FromRequestParts<S> for Path<T>
+where
+    T: DeserializeOwned + Send,
+    S: Send + Sync,
+{
+    type Rejection = Error;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Error> {
+        match extract::Path::from_request_parts(parts, state).await {
+            Ok(extract::Path(v)) => Ok(Self(v)),
+            Err(err) => Err(Error::new(err.status(), err.body_text())),
+        }
+    }
+}
+
+pub(super) struct Query<T>(pub(super) T);
+
+#[async_trait]
+impl<T, S> FromRequestParts<S> for Query<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = Error;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Error> {
+        match extract::Query::from_request_parts(parts, state).await {
+            Ok(extract::Query(v)) => Ok(Self(v)),
+            Err(err) => Err(Error::new(err.status(), err.body_text())),
+        }
+    }
+}
+
+pub(super) struct Json<T>(pub(super) T);
+
+#[async_trait]
+impl<T, S> FromRequest<S> for Json<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = Error;
+
+    async fn from_request(request: Request, state: &S) -> Result<Self, Error> {
+        match axum::Json::from_request(request, state).await {
+            Ok(axum::Json(v)) => Ok(Self(v)),
+            Err(err) => Err(Error::new(err.status(), err.body_text())),
+        }
+    }
+}
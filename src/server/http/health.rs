@@ -0,0 +1,123 @@
+use super::AppState;
+use crate::core::entity::DatabaseTransaction;
+
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::Json;
+use serde::Serialize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Status {
+    Ok,
+    Fail,
+}
+
+#[derive(Serialize)]
+pub(super) struct Report {
+    status: Status,
+    checks: BTreeMap<&'static str, Check>,
+}
+
+#[derive(Serialize)]
+struct Check {
+    status: Status,
+    duration_ms: u64,
+    // Generic, as the probes are not authenticated. The details are logged.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<&'static str>,
+    // A problem which does not fail the check.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<&'static str>,
+}
+
+impl Check {
+    fn new(name: &str, result: Result<()>, reason: &'static str, duration: Duration) -> Self {
+        let (status, error) = match result {
+            Ok(_) => (Status::Ok, None),
+            Err(err) => {
+                log::warn!("readiness check failed: {name}: {err:#}");
+                (Status::Fail, Some(reason))
+            }
+        };
+        Self {
+            status,
+            duration_ms: duration.as_millis() as u64,
+            error,
+            detail: None,
+        }
+    }
+}
+
+// Liveness: the process is up and able to serve requests.
+pub(super) async fn healthz() -> Json<Report> {
+    Json(Report {
+        status: Status::Ok,
+        checks: BTreeMap::new(),
+    })
+}
+
+// Readiness: all the dependencies needed to serve the API are available.
+pub(super) async fn readyz<T>(State(state): State<Arc<AppState<T>>>) -> (StatusCode, Json<Report>)
+where
+    T: DatabaseTransaction + Send + Sync,
+{
+    let health = &state.health;
+    let (database, migrations) = tokio::join!(
+        check(
+            "database",
+            "database is unavailable",
+            Duration::from_millis(health.database_timeout),
+            state.controller.ping()
+        ),
+        check(
+            "migrations",
+            "database schema is not up to date",
+            Duration::from_millis(health.schema_timeout),
+            state.controller.check_schema()
+        ),
+    );
+    let mut checks = BTreeMap::from([("database", database), ("migrations", migrations)]);
+    if let Some(tls) = &state.tls_status {
+        let mut check = Check::new(
+            "tls",
+            tls.check(),
+            "TLS certificate is not valid",
+            Duration::ZERO,
+        );
+        // Logged and counted by the watcher already.
+        if tls.any_reload_failed() {
+            check.detail = Some("TLS certificate reload failed, serving the previous one");
+        }
+        checks.insert("tls", check);
+    }
+
+    let status = match checks.values().all(|v| v.status == Status::Ok) {
+        true => Status::Ok,
+        false => Status::Fail,
+    };
+    let code = match status {
+        Status::Ok => StatusCode::OK,
+        Status::Fail => StatusCode::SERVICE_UNAVAILABLE,
+    };
+
+    (code, Json(Report { status, checks }))
+}
+
+async fn check<F>(name: &str, reason: &'static str, timeout: Duration, f: F) -> Check
+where
+    F: Future<Output = Result<()>>,
+{
+    let started = Instant::now();
+    let result = match tokio::time::timeout(timeout, f).await {
+        Ok(v) => v,
+        Err(_) => Err(anyhow!("timed out after {} ms", timeout.as_millis())),
+    };
+    Check::new(name, result, reason, started.elapsed())
+}
@@ -0,0 +1,132 @@
+use super::error::Error;
+use super::AppState;
+use crate::core::controller::{IdempotencyKey, Idempotent};
+use crate::core::entity::{DatabaseTransaction, StoredResponse};
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use axum::body::Body;
+use axum::http::{header, HeaderMap, HeaderName, HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tokio::sync::watch;
+use tokio::time::MissedTickBehavior;
+
+static IDEMPOTENCY_KEY: HeaderName = HeaderName::from_static("idempotency-key");
+static IDEMPOTENT_REPLAYED: HeaderName = HeaderName::from_static("idempotent-replayed");
+const MAX_LENGTH: usize = 255;
+
+/// Takes the `Idempotency-Key` header of a request. The fingerprint covers the
+/// operation and the payload, so that reusing the key for another request is
+/// detected.
+pub(super) fn key<P>(
+    headers: &HeaderMap,
+    operation: &str,
+    payload: &P,
+    ttl: u64,
+) -> Result<Option<IdempotencyKey>, Error>
+where
+    P: Serialize,
+{
+    let Some(value) = headers.get(&IDEMPOTENCY_KEY) else {
+        return Ok(None);
+    };
+    let bytes = value.as_bytes();
+    if bytes.is_empty() || bytes.len() > MAX_LENGTH || !bytes.iter().all(u8::is_ascii_graphic) {
+        return Err(Error::new(
+            StatusCode::BAD_REQUEST,
+            format!("idempotency key has to be 1 to {MAX_LENGTH} visible ASCII characters"),
+        ));
+    }
+    let payload = serde_json::to_vec(payload)
+        .map_err(|err| Error::internal("serialize the payload", err.into()))?;
+    let mut hasher = Sha256::new();
+    hasher.update(operation.as_bytes());
+    hasher.update([0]);
+    hasher.update(&payload);
+
+    Ok(Some(IdempotencyKey {
+        key: String::from_utf8_lossy(bytes).into_owned(),
+        fingerprint: format!("{:x}", hasher.finalize()),
+        ttl,
+    }))
+}
+
+/// Makes the response to store for `key`.
+pub(super) fn store<B>(
+    key: &IdempotencyKey,
+    status: StatusCode,
+    headers: &[(HeaderName, String)],
+    body: &B,
+) -> Result<StoredResponse>
+where
+    B: Serialize,
+{
+    Ok(StoredResponse {
+        fingerprint: key.fingerprint.clone(),
+        status: status.as_u16(),
+        headers: headers
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.clone()))
+            .collect(),
+        body: serde_json::to_vec(body)?,
+    })
+}
+
+pub(super) fn respond(result: Idempotent) -> Response {
+    match result {
+        Idempotent::Stored(v) => replay(v),
+        Idempotent::Replayed(v) => {
+            let mut response = replay(v);
+            response.headers_mut().insert(
+                IDEMPOTENT_REPLAYED.clone(),
+                HeaderValue::from_static("true"),
+            );
+            response
+        }
+        Idempotent::Mismatched => Error::new(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "idempotency key has been used for another request",
+        )
+        .into_response(),
+    }
+}
+
+fn replay(stored: StoredResponse) -> Response {
+    let mut builder = Response::builder()
+        .status(stored.status)
+        .header(header::CONTENT_TYPE, "application/json");
+    for (name, value) in &stored.headers {
+        builder = builder.header(name, value);
+    }
+    match builder.body(Body::from(stored.body)) {
+        Ok(v) => v,
+        Err(err) => Error::internal("replay a stored response", err.into()).into_response(),
+    }
+}
+
+/// Deletes the expired responses every `interval` until shutdown.
+pub(super) async fn purge<T>(
+    state: Arc<AppState<T>>,
+    interval: Duration,
+    mut shutdown: watch::Receiver<bool>,
+) where
+    T: DatabaseTransaction + Send + Sync,
+{
+    let mut interval = tokio::time::interval(interval);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = shutdown.changed() => return,
+        }
+        match state.controller.purge_stored_responses().await {
+            Ok(0) => {}
+            Ok(v) => log::info!("purged expired idempotency keys: count = {v}"),
+            Err(err) => log::error!("failed to purge expired idempotency keys: {err:?}"),
+        }
+    }
+}
@@ -0,0 +1,142 @@
+use super::error::Error;
+use crate::configuration;
+use crate::metrics::METRICS;
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::body::Body;
+use axum::extract::{MatchedPath, Request, State};
+use axum::http::{header, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use http_body_util::Limited;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+struct RouteLimits {
+    path: String,
+    method: Option<String>,
+    timeout: Option<Duration>,
+    max_body_size: Option<usize>,
+    concurrency: Option<Arc<Semaphore>>,
+}
+
+/// Applies the timeout, the body size limit and the concurrency limits of the
+/// routes. The requests over the concurrency limits are rejected at once rather
+/// than queued, so that the server keeps up with the ones it has accepted.
+pub(super) struct Limiter {
+    timeout: Duration,
+    max_body_size: usize,
+    concurrency: Option<Arc<Semaphore>>,
+    routes: Vec<RouteLimits>,
+}
+
+impl Limiter {
+    pub(super) fn new(config: configuration::Limits) -> Self {
+        let semaphore = |v: usize| Arc::new(Semaphore::new(v));
+        let routes = config
+            .routes
+            .into_iter()
+            .map(|v| RouteLimits {
+                path: v.path,
+                method: v.method.map(|v| v.to_uppercase()),
+                timeout: v.timeout.map(Duration::from_secs),
+                max_body_size: v.max_body_size,
+                concurrency: v.max_concurrent_requests.map(semaphore),
+            })
+            .collect();
+
+        Self {
+            timeout: Duration::from_secs(config.timeout),
+            max_body_size: config.max_body_size,
+            concurrency: config.max_concurrent_requests.map(semaphore),
+            routes,
+        }
+    }
+
+    fn route(&self, method: &str, route: &str) -> Option<&RouteLimits> {
+        self.routes
+            .iter()
+            .find(|v| v.path == route && v.method.as_deref().is_none_or(|v| v == method))
+    }
+}
+
+pub(super) async fn enforce(
+    State(limiter): State<Arc<Limiter>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let method = request.method().to_string();
+    let route = match request.extensions().get::<MatchedPath>() {
+        Some(v) => v.as_str().to_string(),
+        None => String::from("unmatched"),
+    };
+    let limits = limiter.route(&method, &route);
+
+    // Held until the response is ready.
+    let mut permits = Vec::new();
+    let semaphores = [
+        limiter.concurrency.as_ref(),
+        limits.and_then(|v| v.concurrency.as_ref()),
+    ];
+    for semaphore in semaphores.into_iter().flatten() {
+        match acquire(semaphore) {
+            Some(v) => permits.push(v),
+            None => {
+                return reject(
+                    &route,
+                    "overloaded",
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "server is overloaded",
+                );
+            }
+        }
+    }
+
+    let max_body_size = limits
+        .and_then(|v| v.max_body_size)
+        .unwrap_or(limiter.max_body_size);
+    let content_length = request
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<usize>().ok());
+    if content_length.is_some_and(|v| v > max_body_size) {
+        return reject(
+            &route,
+            "too_large",
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "request body is too large",
+        );
+    }
+    // The length is unknown in advance if the body is chunked, so the
+    // extractors fail with 413 once it has gone over the limit.
+    let request = request.map(|v| Body::new(Limited::new(v, max_body_size)));
+
+    let timeout = limits.and_then(|v| v.timeout).unwrap_or(limiter.timeout);
+    // Dropping the handler on timeout rolls back its transaction.
+    match tokio::time::timeout(timeout, next.run(request)).await {
+        Ok(v) => v,
+        Err(_) => {
+            log::warn!("request has timed out: {method} {route}");
+            reject(
+                &route,
+                "timeout",
+                StatusCode::GATEWAY_TIMEOUT,
+                "request has timed out",
+            )
+        }
+    }
+}
+
+fn acquire(semaphore: &Arc<Semaphore>) -> Option<OwnedSemaphorePermit> {
+    semaphore.clone().try_acquire_owned().ok()
+}
+
+fn reject(route: &str, reason: &str, status: StatusCode, message: &str) -> Response {
+    METRICS
+        .http_rejected_requests
+        .with_label_values(&[route, reason])
+        .inc();
+    Error::new(status, message).into_response()
+}
@@ -0,0 +1,131 @@
+use super::tls::{self, ClientIdentityAcceptor};
+use super::{redirect, unix};
+use crate::configuration::{self, RouteGroup};
+
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use axum::Router;
+use axum_server::tls_rustls::RustlsConfig;
+use axum_server::Handle;
+use tokio::sync::watch;
+
+pub(super) struct Listener {
+    address: Address,
+    tls: Option<(configuration::TLS, RustlsConfig)>,
+    tls_status: Arc<tls::Status>,
+    routes: Vec<RouteGroup>,
+    redirect_to: Option<u16>,
+}
+
+enum Address {
+    Tcp(SocketAddr),
+    Unix(configuration::UnixSocket),
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Address::Tcp(addr) => write!(f, "{addr}"),
+            Address::Unix(socket) => write!(f, "unix:{}", socket.path),
+        }
+    }
+}
+
+/// Tells the listeners to stop accepting new connections and to wait for the
+/// in-flight requests up to the grace period.
+#[derive(Clone)]
+pub(super) struct Shutdown {
+    pub(super) handle: Handle,
+    pub(super) signal: watch::Receiver<bool>,
+    pub(super) grace_period: Duration,
+}
+
+impl Listener {
+    pub(super) async fn new(
+        config: configuration::Listener,
+        tls_status: Arc<tls::Status>,
+    ) -> Result<Self> {
+        let address = match (config.port, config.unix) {
+            (Some(port), None) => Address::Tcp(SocketAddr::new(config.address, port)),
+            (None, Some(socket)) => {
+                if config.tls.is_some() || config.redirect_to.is_some() {
+                    return Err(anyhow!(
+                        "TLS and redirection are not supported on Unix socket: {}",
+                        socket.path
+                    ));
+                }
+                Address::Unix(socket)
+            }
+            _ => return Err(anyhow!("either port or unix has to be set for a listener")),
+        };
+        let tls = match config.tls {
+            Some(tls) => {
+                let rustls = tls::load(&tls, &tls_status).await.context(format!(
+                    "failed to load TLS configuration: {}, {}",
+                    tls.cert_file, tls.key_file
+                ))?;
+                Some((tls, rustls))
+            }
+            None => None,
+        };
+
+        Ok(Self {
+            address,
+            tls,
+            tls_status,
+            routes: config.routes,
+            redirect_to: config.redirect_to,
+        })
+    }
+
+    pub(super) fn has_tls(&self) -> bool {
+        self.tls.is_some()
+    }
+
+    pub(super) fn routes(&self) -> &[RouteGroup] {
+        &self.routes
+    }
+
+    /// Serves `app`, or the HTTPS redirection if this is a redirect listener,
+    /// until `shutdown` is signaled.
+    pub(super) async fn serve(self, app: Router, shutdown: Shutdown) -> Result<()> {
+        let app = match self.redirect_to {
+            Some(port) => redirect::router(port),
+            None => app,
+        };
+        log::info!("listening on {}: tls = {}", self.address, self.has_tls());
+
+        let addr = match self.address {
+            Address::Tcp(addr) => addr,
+            Address::Unix(socket) => {
+                return unix::serve(&socket, app, shutdown.signal, shutdown.grace_period).await;
+            }
+        };
+        match self.tls {
+            Some((config, rustls)) => {
+                tokio::spawn(tls::watch(
+                    config,
+                    rustls.clone(),
+                    self.tls_status,
+                    shutdown.signal,
+                ));
+                axum_server::bind(addr)
+                    .acceptor(ClientIdentityAcceptor::new(rustls))
+                    .handle(shutdown.handle)
+                    .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+                    .await
+            }
+            None => {
+                axum_server::bind(addr)
+                    .handle(shutdown.handle)
+                    .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+                    .await
+            }
+        }
+        .context(format!("failed to bind HTTP server: {addr}"))
+    }
+}
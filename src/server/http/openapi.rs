@@ -0,0 +1,41 @@
+use super::{search, users};
+
+use axum::Json;
+use utoipa::{Modify, OpenApi};
+
+/// OpenAPI document of the API routes. `openapi.json` at the root of the
+/// repository is generated from it, and a test checks that they are in sync.
+#[derive(OpenApi)]
+#[openapi(
+    paths(
+        super::create_user,
+        super::get_user,
+        users::create_user,
+        users::get_user,
+        users::update_user,
+        users::delete_user,
+        users::restore_user,
+        users::list_users,
+        search::search_users,
+    ),
+    tags(
+        (name = "v1", description = "RPC-style API, kept for compatibility."),
+        (name = "v2", description = "Resource-oriented API."),
+    ),
+    modifiers(&Info),
+)]
+pub struct ApiDoc;
+
+struct Info;
+
+impl Modify for Info {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        // Filled from the package metadata, which has neither of them.
+        openapi.info.description = None;
+        openapi.info.license = None;
+    }
+}
+
+pub(super) async fn spec() -> Json<utoipa::openapi::OpenApi> {
+    Json(ApiDoc::openapi())
+}
@@ -0,0 +1,381 @@
+use super::error::Error;
+use super::ClientIdentity;
+use crate::configuration::{self, Limit, RateLimitKey};
+use crate::metrics::METRICS;
+
+use std::net::SocketAddr;
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use axum::extract::{ConnectInfo, MatchedPath, Request, State};
+use axum::http::{header, HeaderMap, HeaderName, HeaderValue, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use lru::LruCache;
+
+static RATELIMIT_LIMIT: HeaderName = HeaderName::from_static("ratelimit-limit");
+static RATELIMIT_REMAINING: HeaderName = HeaderName::from_static("ratelimit-remaining");
+static RATELIMIT_RESET: HeaderName = HeaderName::from_static("ratelimit-reset");
+
+// Idle buckets are removed once per this many requests.
+const CLEANUP_INTERVAL: u64 = 1024;
+// Clients beyond this many evict the least recently seen one, so that a flood
+// of distinct keys can't exhaust the memory.
+const MAX_BUCKETS: NonZeroUsize = NonZeroUsize::new(100_000).unwrap();
+
+/// Keeps the token buckets. The in-process `MemoryStore` limits each instance
+/// separately; an implementation backed by a shared database can enforce the
+/// limits across all the instances instead.
+#[async_trait]
+pub(super) trait Store: Send + Sync {
+    /// Takes a token from the bucket identified by `key`.
+    async fn acquire(&self, key: &str, bucket: &Bucket) -> Result<Decision>;
+}
+
+/// Parameters of a token bucket.
+#[derive(Debug, Clone, Copy)]
+pub(super) struct Bucket {
+    /// Tokens added per second.
+    pub(super) rate: f64,
+    pub(super) capacity: u32,
+}
+
+impl From<&Limit> for Bucket {
+    fn from(limit: &Limit) -> Self {
+        Self {
+            rate: limit.requests as f64 / limit.period.max(1) as f64,
+            capacity: limit.burst.unwrap_or(limit.requests).max(1),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(super) struct Decision {
+    pub(super) allowed: bool,
+    pub(super) remaining: u32,
+    /// Until the bucket is full again.
+    pub(super) reset: Duration,
+    /// Until the next token if not allowed.
+    pub(super) retry_after: Duration,
+}
+
+pub(super) struct MemoryStore {
+    // Least recently seen first, which a new client evicts in constant time
+    // once the store is full.
+    buckets: Mutex<LruCache<String, Tokens>>,
+    counter: AtomicU64,
+}
+
+impl Default for MemoryStore {
+    fn default() -> Self {
+        Self::new(MAX_BUCKETS)
+    }
+}
+
+struct Tokens {
+    bucket: Bucket,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Tokens {
+    fn tokens_at(&self, now: Instant) -> f64 {
+        let added = now.duration_since(self.updated).as_secs_f64() * self.bucket.rate;
+        (self.tokens + added).min(self.bucket.capacity as f64)
+    }
+
+    fn refill(&mut self, now: Instant) {
+        self.tokens = self.tokens_at(now);
+        self.updated = now;
+    }
+}
+
+impl MemoryStore {
+    fn new(max_buckets: NonZeroUsize) -> Self {
+        Self {
+            buckets: Mutex::new(LruCache::new(max_buckets)),
+            counter: AtomicU64::default(),
+        }
+    }
+
+    fn take(&self, key: &str, bucket: &Bucket, now: Instant) -> Decision {
+        let mut buckets = self.buckets.lock().unwrap();
+        if self
+            .counter
+            .fetch_add(1, Ordering::Relaxed)
+            .is_multiple_of(CLEANUP_INTERVAL)
+        {
+            cleanup(&mut buckets, now);
+        }
+
+        let v = buckets.get_or_insert_mut(key.to_string(), || Tokens {
+            bucket: *bucket,
+            tokens: bucket.capacity as f64,
+            updated: now,
+        });
+        v.bucket = *bucket;
+        v.refill(now);
+        let allowed = v.tokens >= 1.0;
+        if allowed {
+            v.tokens -= 1.0;
+        }
+
+        Decision {
+            allowed,
+            remaining: v.tokens.floor() as u32,
+            reset: Duration::from_secs_f64((bucket.capacity as f64 - v.tokens) / bucket.rate),
+            retry_after: Duration::from_secs_f64((1.0 - v.tokens).max(0.0) / bucket.rate),
+        }
+    }
+}
+
+// A bucket that would be full by now is the same as a missing one.
+fn cleanup(buckets: &mut LruCache<String, Tokens>, now: Instant) {
+    let full = buckets
+        .iter()
+        .filter(|(_, v)| v.tokens_at(now) >= v.bucket.capacity as f64)
+        .map(|(k, _)| k.clone())
+        .collect::<Vec<_>>();
+    for key in full {
+        buckets.pop(&key);
+    }
+}
+
+#[async_trait]
+impl Store for MemoryStore {
+    async fn acquire(&self, key: &str, bucket: &Bucket) -> Result<Decision> {
+        Ok(self.take(key, bucket, Instant::now()))
+    }
+}
+
+struct RouteBucket {
+    path: String,
+    method: Option<String>,
+    bucket: Bucket,
+}
+
+/// Limits the rate of the requests per client and route with token buckets.
+pub(super) struct RateLimiter {
+    key: RateLimitKey,
+    api_key_header: HeaderName,
+    default: Option<Bucket>,
+    routes: Vec<RouteBucket>,
+    store: Arc<dyn Store>,
+}
+
+impl RateLimiter {
+    pub(super) fn new(config: configuration::RateLimit, store: Arc<dyn Store>) -> Result<Self> {
+        let api_key_header = HeaderName::try_from(config.api_key_header.as_str())
+            .map_err(|_| anyhow!("invalid API key header: {}", config.api_key_header))?;
+        let mut limits = config
+            .default
+            .iter()
+            .chain(config.routes.iter().map(|v| &v.limit));
+        if limits.any(|v| v.requests == 0) {
+            return Err(anyhow!("requests of a rate limit has to be positive"));
+        }
+        let routes = config
+            .routes
+            .iter()
+            .map(|v| RouteBucket {
+                path: v.path.clone(),
+                method: v.method.as_ref().map(|v| v.to_uppercase()),
+                bucket: Bucket::from(&v.limit),
+            })
+            .collect();
+
+        Ok(Self {
+            key: config.key,
+            api_key_header,
+            default: config.default.as_ref().map(Bucket::from),
+            routes,
+            store,
+        })
+    }
+
+    fn bucket(&self, method: &str, route: &str) -> Option<Bucket> {
+        self.routes
+            .iter()
+            .find(|v| v.path == route && v.method.as_deref().is_none_or(|v| v == method))
+            .map(|v| v.bucket)
+            .or(self.default)
+    }
+
+    fn client(&self, request: &Request) -> String {
+        let extensions = request.extensions();
+        let ip = match extensions.get::<ConnectInfo<SocketAddr>>() {
+            Some(v) => format!("ip:{}", v.0.ip()),
+            // Unix socket, which only local clients can connect to.
+            None => String::from("local"),
+        };
+        match self.key {
+            RateLimitKey::Ip => ip,
+            // The API key isn't validated here, so a client could get a fresh
+            // bucket per request by making keys up, unless it's paired with
+            // the address.
+            RateLimitKey::ApiKey => match request
+                .headers()
+                .get(&self.api_key_header)
+                .and_then(|v| v.to_str().ok())
+            {
+                Some(key) => format!("key:{key} {ip}"),
+                None => ip,
+            },
+            RateLimitKey::User => match extensions.get::<Option<ClientIdentity>>() {
+                Some(Some(identity)) => format!("user:{}", identity.subject),
+                _ => ip,
+            },
+        }
+    }
+}
+
+pub(super) async fn limit(
+    State(limiter): State<Arc<RateLimiter>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let method = request.method().to_string();
+    let route = match request.extensions().get::<MatchedPath>() {
+        Some(v) => v.as_str().to_string(),
+        None => return next.run(request).await,
+    };
+    let Some(bucket) = limiter.bucket(&method, &route) else {
+        return next.run(request).await;
+    };
+
+    let key = format!("{method} {route} {}", limiter.client(&request));
+    let decision = match limiter.store.acquire(&key, &bucket).await {
+        Ok(v) => v,
+        Err(err) => {
+            // Serving without the limit is better than failing every request.
+            log::error!("failed to check the rate limit: {err:?}");
+            return next.run(request).await;
+        }
+    };
+
+    let mut response = if decision.allowed {
+        next.run(request).await
+    } else {
+        METRICS.http_rate_limited.with_label_values(&[&route]).inc();
+        let mut response =
+            Error::new(StatusCode::TOO_MANY_REQUESTS, "too many requests").into_response();
+        insert_secs(
+            response.headers_mut(),
+            &header::RETRY_AFTER,
+            decision.retry_after,
+        );
+        response
+    };
+    let headers = response.headers_mut();
+    headers.insert(RATELIMIT_LIMIT.clone(), HeaderValue::from(bucket.capacity));
+    headers.insert(
+        RATELIMIT_REMAINING.clone(),
+        HeaderValue::from(decision.remaining),
+    );
+    insert_secs(headers, &RATELIMIT_RESET, decision.reset);
+    response
+}
+
+// Rounds up, so that the client does not retry too early.
+fn insert_secs(headers: &mut HeaderMap, name: &HeaderName, duration: Duration) {
+    let secs = duration.as_secs_f64().ceil() as u64;
+    headers.insert(name.clone(), HeaderValue::from(secs));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BUCKET: Bucket = Bucket {
+        rate: 1.0,
+        capacity: 2,
+    };
+
+    #[test]
+    fn bucket_runs_out() {
+        let store = MemoryStore::default();
+        let now = Instant::now();
+        assert!(store.take("a", &BUCKET, now).allowed);
+        let decision = store.take("a", &BUCKET, now);
+        assert!(decision.allowed);
+        assert_eq!(decision.remaining, 0);
+        assert_eq!(decision.reset, Duration::from_secs(2));
+
+        let decision = store.take("a", &BUCKET, now);
+        assert!(!decision.allowed);
+        assert_eq!(decision.retry_after, Duration::from_secs(1));
+        // Other clients have their own buckets.
+        assert!(store.take("b", &BUCKET, now).allowed);
+    }
+
+    #[test]
+    fn bucket_refills() {
+        let store = MemoryStore::default();
+        let now = Instant::now();
+        store.take("a", &BUCKET, now);
+        store.take("a", &BUCKET, now);
+        assert!(!store.take("a", &BUCKET, now).allowed);
+
+        let decision = store.take("a", &BUCKET, now + Duration::from_millis(1500));
+        assert!(decision.allowed);
+        assert_eq!(decision.remaining, 0);
+        // Never above the capacity, however long the client was idle.
+        let decision = store.take("a", &BUCKET, now + Duration::from_secs(60));
+        assert_eq!(decision.remaining, 1);
+    }
+
+    #[test]
+    fn cleanup_removes_full_buckets() {
+        let store = MemoryStore::default();
+        let now = Instant::now();
+        store.take("a", &BUCKET, now);
+        store.take("b", &BUCKET, now + Duration::from_secs(1));
+        store.take("b", &BUCKET, now + Duration::from_secs(1));
+
+        let mut buckets = store.buckets.lock().unwrap();
+        cleanup(&mut buckets, now + Duration::from_secs(2));
+        assert!(!buckets.contains("a"));
+        assert!(buckets.contains("b"));
+    }
+
+    #[test]
+    fn least_recently_seen_bucket_is_evicted() {
+        let store = MemoryStore::new(NonZeroUsize::new(2).unwrap());
+        let now = Instant::now();
+        store.take("a", &BUCKET, now);
+        store.take("b", &BUCKET, now + Duration::from_millis(100));
+        // Seen again, so b is the least recently seen one now.
+        store.take("a", &BUCKET, now + Duration::from_millis(200));
+        store.take("c", &BUCKET, now + Duration::from_millis(300));
+
+        let buckets = store.buckets.lock().unwrap();
+        assert_eq!(buckets.len(), 2);
+        assert!(buckets.contains("a"));
+        assert!(!buckets.contains("b"));
+    }
+
+    #[test]
+    fn full_store_stays_bounded() {
+        let max_buckets = NonZeroUsize::new(100).unwrap();
+        let store = MemoryStore::new(max_buckets);
+        let now = Instant::now();
+        // A flood of new clients, none of whose buckets is full again by the
+        // cleanups, so each of them has to evict another one.
+        for i in 0..10 * CLEANUP_INTERVAL {
+            let decision = store.take(&format!("client{i}"), &BUCKET, now);
+            assert!(decision.allowed);
+            assert!(store.buckets.lock().unwrap().len() <= max_buckets.get());
+        }
+
+        let buckets = store.buckets.lock().unwrap();
+        assert_eq!(buckets.len(), max_buckets.get());
+        // The most recent clients are kept.
+        let last = format!("client{}", 10 * CLEANUP_INTERVAL - 1);
+        assert!(buckets.contains(&last));
+        assert!(!buckets.contains("client0"));
+    }
+}
@@ -0,0 +1,47 @@
+use axum::extract::Request;
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Router;
+
+/// Returns a router that permanently redirects every plaintext request to the
+/// same URL over HTTPS on `https_port`.
+pub(super) fn router(https_port: u16) -> Router {
+    Router::new().fallback(move |request: Request| redirect(request, https_port))
+}
+
+async fn redirect(request: Request, https_port: u16) -> Response {
+    let Some(host) = request
+        .headers()
+        .get(header::HOST)
+        .and_then(|v| v.to_str().ok())
+        .map(strip_port)
+    else {
+        return (StatusCode::BAD_REQUEST, "missing Host header").into_response();
+    };
+    let path = request
+        .uri()
+        .path_and_query()
+        .map(|v| v.as_str())
+        .unwrap_or("/");
+    let location = match https_port {
+        443 => format!("https://{host}{path}"),
+        port => format!("https://{host}:{port}{path}"),
+    };
+
+    (
+        StatusCode::PERMANENT_REDIRECT,
+        [(header::LOCATION, location)],
+    )
+        .into_response()
+}
+
+fn strip_port(host: &str) -> &str {
+    // IPv6 literals are enclosed in brackets, e.g., [::1]:80.
+    if let Some(end) = host.rfind(']') {
+        return &host[..=end];
+    }
+    match host.rsplit_once(':') {
+        Some((host, _)) => host,
+        None => host,
+    }
+}
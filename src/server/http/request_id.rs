@@ -0,0 +1,41 @@
+use crate::logger;
+
+use axum::extract::Request;
+use axum::http::{HeaderName, HeaderValue};
+use axum::middleware::Next;
+use axum::response::Response;
+use uuid::Uuid;
+
+static X_REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+const MAX_LENGTH: usize = 128;
+
+/// Identifies a request end to end. Handlers get it from the request extensions,
+/// and it is attached to the log lines written while serving the request.
+#[derive(Debug, Clone)]
+pub struct RequestId(pub String);
+
+/// Takes the request id from the `X-Request-Id` header, e.g., set by a proxy,
+/// or generates one if it is missing or invalid, and returns it in the same
+/// header of the response.
+pub(super) async fn propagate(mut request: Request, next: Next) -> Response {
+    let request_id = match request.headers().get(&X_REQUEST_ID) {
+        Some(v) if is_valid(v) => String::from_utf8_lossy(v.as_bytes()).into_owned(),
+        _ => Uuid::new_v4().to_string(),
+    };
+    request
+        .extensions_mut()
+        .insert(RequestId(request_id.clone()));
+
+    let mut response =
+        logger::context::with_request_id(request_id.clone(), next.run(request)).await;
+    if let Ok(v) = HeaderValue::from_str(&request_id) {
+        response.headers_mut().insert(X_REQUEST_ID.clone(), v);
+    }
+    response
+}
+
+// Rejects the ids that would make the log lines ambiguous or too long.
+fn is_valid(value: &HeaderValue) -> bool {
+    let bytes = value.as_bytes();
+    !bytes.is_empty() && bytes.len() <= MAX_LENGTH && bytes.iter().all(u8::is_ascii_graphic)
+}
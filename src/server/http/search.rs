@@ -0,0 +1,138 @@
+use super::error::{Error, ErrorBody};
+use super::extract::Query;
+use super::users::UserBody;
+use super::AppState;
+use crate::core::controller::SearchUsersParams as ControllerSearchUsersParams;
+use crate::core::entity::{DatabaseTransaction, Highlight, SearchHit};
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::Json;
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub(super) struct SearchQuery {
+    /// Words to find in the usernames and the addresses. A user has to contain
+    /// a word starting with each of them.
+    q: String,
+    limit: Option<u32>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(super) struct SearchResult {
+    /// Most relevant first.
+    hits: Vec<SearchHitBody>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(super) struct SearchHitBody {
+    user: UserBody,
+    /// Relevance, which is only comparable within a result.
+    score: f64,
+    /// Matched fields, HTML-escaped with the matches enclosed in `<em>`.
+    highlights: BTreeMap<String, String>,
+}
+
+impl From<SearchHit> for SearchHitBody {
+    fn from(hit: SearchHit) -> Self {
+        let highlights = hit
+            .highlights
+            .iter()
+            .filter_map(|v| {
+                let text = match v.field {
+                    "username" => &hit.user.username,
+                    "address" => &hit.user.address,
+                    _ => return None,
+                };
+                Some((v.field.to_string(), highlight(text, v)))
+            })
+            .collect();
+        Self {
+            user: hit.user.into(),
+            score: hit.score,
+            highlights,
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v2/users/search",
+    operation_id = "search_users_v2",
+    tag = "v2",
+    params(SearchQuery),
+    responses(
+        (status = 200, description = "Matching users", body = SearchResult),
+        (status = 400, description = "Invalid query", body = ErrorBody),
+        (status = 500, description = "Internal error", body = ErrorBody),
+    ),
+)]
+pub(super) async fn search_users<T>(
+    State(state): State<Arc<AppState<T>>>,
+    Query(query): Query<SearchQuery>,
+) -> Result<Json<SearchResult>, Error>
+where
+    T: DatabaseTransaction + Send + Sync,
+{
+    log::debug!("search_users invoked");
+    let limit = query.limit.unwrap_or(state.default_page_size);
+    if limit == 0 || limit > state.max_page_size {
+        return Err(Error::new(
+            StatusCode::BAD_REQUEST,
+            format!("limit has to be between 1 and {}", state.max_page_size),
+        ));
+    }
+    if !query.q.chars().any(char::is_alphanumeric) {
+        return Err(Error::new(
+            StatusCode::BAD_REQUEST,
+            "q has to contain a letter or a digit",
+        ));
+    }
+
+    let params = ControllerSearchUsersParams {
+        query: query.q,
+        limit,
+    };
+    let hits = state
+        .controller
+        .search_users(params)
+        .await
+        .map_err(|err| Error::internal("search users", err))?;
+
+    Ok(Json(SearchResult {
+        hits: hits.into_iter().map(SearchHitBody::from).collect(),
+    }))
+}
+
+// The ranges are ordered and disjoint, as they are within distinct words.
+fn highlight(text: &str, highlight: &Highlight) -> String {
+    let mut result = String::new();
+    let mut end = 0;
+    for range in &highlight.ranges {
+        escape(&mut result, &text[end..range.start]);
+        result.push_str("<em>");
+        escape(&mut result, &text[range.clone()]);
+        result.push_str("</em>");
+        end = range.end;
+    }
+    escape(&mut result, &text[end..]);
+    result
+}
+
+fn escape(result: &mut String, text: &str) {
+    for c in text.chars() {
+        match c {
+            '&' => result.push_str("&amp;"),
+            '<' => result.push_str("&lt;"),
+            '>' => result.push_str("&gt;"),
+            '"' => result.push_str("&quot;"),
+            '\'' => result.push_str("&#39;"),
+            _ => result.push(c),
+        }
+    }
+}
@@ -0,0 +1,507 @@
+use crate::configuration::{self, ClientAuthMode, TlsVersion};
+use crate::metrics::METRICS;
+
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use anyhow::{anyhow, Context, Result};
+use axum::middleware::AddExtension;
+use axum::Extension;
+use axum_server::accept::Accept;
+use axum_server::tls_rustls::{RustlsAcceptor, RustlsConfig};
+use futures::future::BoxFuture;
+use rustls::server::{
+    AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient, ClientCertVerifier,
+    ClientHello, ResolvesServerCert, UnparsedCertRevocationList,
+};
+use rustls::sign::{self, CertifiedKey};
+use rustls::{
+    Certificate, PrivateKey, RootCertStore, ServerConfig, SignatureScheme, SupportedCipherSuite,
+    SupportedProtocolVersion, ALL_CIPHER_SUITES, DEFAULT_CIPHER_SUITES,
+};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::watch;
+use tokio::time::MissedTickBehavior;
+use tower::Layer;
+use x509_parser::extensions::GeneralName;
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+/// Identity of a client that has authenticated itself with a certificate
+/// verified against the configured client CA. Handlers get it from the request
+/// extensions as `Option<ClientIdentity>`, which is `None` if the client has not
+/// presented a certificate.
+#[derive(Debug, Clone)]
+pub struct ClientIdentity {
+    pub subject: String,
+    pub sans: Vec<String>,
+}
+
+/// State of the certificates served by the TLS listeners, which the readiness
+/// check reports. `watch` keeps it up to date.
+#[derive(Debug, Default)]
+pub(super) struct Status {
+    // By the default cert file of the listeners.
+    listeners: Mutex<HashMap<String, CertStatus>>,
+}
+
+#[derive(Debug, Clone)]
+struct CertStatus {
+    // Expiry of every served certificate.
+    certs: Vec<(String, i64)>,
+    reload_failed: bool,
+}
+
+impl Status {
+    fn loaded(&self, config: &configuration::TLS, certs: Vec<(String, i64)>) {
+        let status = CertStatus {
+            certs,
+            reload_failed: false,
+        };
+        self.listeners
+            .lock()
+            .unwrap()
+            .insert(config.cert_file.clone(), status);
+    }
+
+    fn reload_failed(&self, config: &configuration::TLS) {
+        if let Some(v) = self.listeners.lock().unwrap().get_mut(&config.cert_file) {
+            v.reload_failed = true;
+        }
+    }
+
+    fn certs(&self, config: &configuration::TLS) -> Vec<(String, i64)> {
+        let listeners = self.listeners.lock().unwrap();
+        listeners
+            .get(&config.cert_file)
+            .map(|v| v.certs.clone())
+            .unwrap_or_default()
+    }
+
+    /// Fails if a served certificate has expired. A failed reload does not fail
+    /// it, as the previous certificate is still served.
+    pub(super) fn check(&self) -> Result<()> {
+        let now = chrono::Utc::now().timestamp();
+        for v in self.listeners.lock().unwrap().values() {
+            if let Some((cert_file, _)) = v.certs.iter().find(|(_, v)| *v <= now) {
+                return Err(anyhow!("TLS certificate has expired: {cert_file}"));
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the last reload of a listener has failed.
+    pub(super) fn any_reload_failed(&self) -> bool {
+        self.listeners
+            .lock()
+            .unwrap()
+            .values()
+            .any(|v| v.reload_failed)
+    }
+}
+
+pub(super) async fn load(config: &configuration::TLS, status: &Status) -> Result<RustlsConfig> {
+    let (server_config, certs) = server_config(config).await?;
+    status.loaded(config, certs);
+    Ok(RustlsConfig::from_config(server_config))
+}
+
+/// Checks the cert, key and CRL files every `reload_interval` and serves the new
+/// certificates once they have been replaced with valid pairs. The current one
+/// is kept otherwise, e.g., if only one of the files has been written yet. The
+/// established connections are not affected. The expiry of the served
+/// certificates is checked as well, and logged whenever it gets closer to a
+/// warning or an error.
+pub(super) async fn watch(
+    config: configuration::TLS,
+    rustls: RustlsConfig,
+    status: Arc<Status>,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    if config.reload_interval == 0 {
+        return;
+    }
+
+    let mut interval = tokio::time::interval(Duration::from_secs(config.reload_interval));
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut last = modified(&config).await;
+    // Already reported on load.
+    let mut levels = expiry_levels(&status.certs(&config), config.expiry_warning);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = shutdown.changed() => return,
+        }
+        for (cert_file, not_after) in status.certs(&config) {
+            let level = expiry_level(not_after, config.expiry_warning);
+            if levels.insert(cert_file.clone(), level) != Some(level) {
+                log_expiry(&cert_file, not_after, level);
+            }
+        }
+
+        let current = modified(&config).await;
+        if current == last {
+            continue;
+        }
+        last = current;
+
+        match server_config(&config).await {
+            Ok((v, certs)) => {
+                rustls.reload_from_config(v);
+                levels = expiry_levels(&certs, config.expiry_warning);
+                status.loaded(&config, certs);
+                METRICS.tls_reloads.with_label_values(&["success"]).inc();
+                log::info!("reloaded TLS certificates: {}", config.cert_file);
+            }
+            Err(err) => {
+                METRICS.tls_reloads.with_label_values(&["failure"]).inc();
+                status.reload_failed(&config);
+                log::error!("failed to reload TLS certificate, keeping the current one: {err:?}");
+            }
+        }
+    }
+}
+
+// Modification time and size of every cert, key and CRL file, None if unreadable.
+async fn modified(config: &configuration::TLS) -> Vec<Option<(SystemTime, u64)>> {
+    let crl_files = config.client_auth.iter().flat_map(|v| &v.crl_files);
+    let paths = key_pairs(config)
+        .flat_map(|(cert_file, key_file)| [cert_file, key_file])
+        .chain(crl_files.map(String::as_str));
+    let mut result = Vec::new();
+    for path in paths {
+        let metadata = tokio::fs::metadata(path).await.ok();
+        result.push(metadata.and_then(|v| Some((v.modified().ok()?, v.len()))));
+    }
+    result
+}
+
+// The default cert and key files followed by the SNI ones.
+fn key_pairs(config: &configuration::TLS) -> impl Iterator<Item = (&str, &str)> {
+    std::iter::once((config.cert_file.as_str(), config.key_file.as_str())).chain(
+        config
+            .sni
+            .iter()
+            .map(|v| (v.cert_file.as_str(), v.key_file.as_str())),
+    )
+}
+
+// Also returns the expiry of every certificate.
+async fn server_config(
+    config: &configuration::TLS,
+) -> Result<(Arc<ServerConfig>, Vec<(String, i64)>)> {
+    let mut keys = Vec::new();
+    for (cert_file, key_file) in key_pairs(config) {
+        keys.push(certified_key(cert_file, key_file).await?);
+    }
+    let mut resolver = CertResolver {
+        default: keys[0].0.clone(),
+        by_name: HashMap::new(),
+    };
+    for (sni, (key, _)) in config.sni.iter().zip(&keys[1..]) {
+        for name in &sni.server_names {
+            resolver.by_name.insert(name.to_lowercase(), key.clone());
+        }
+    }
+
+    let builder = ServerConfig::builder()
+        .with_cipher_suites(&cipher_suites(&config.cipher_suites)?)
+        .with_safe_default_kx_groups()
+        .with_protocol_versions(&protocol_versions(&config.versions))
+        .context("no cipher suite is available for the TLS protocol versions")?;
+    let builder = match &config.client_auth {
+        Some(client_auth) => builder.with_client_cert_verifier(client_verifier(client_auth).await?),
+        None => builder.with_no_client_auth(),
+    };
+    let mut server_config = builder.with_cert_resolver(Arc::new(resolver));
+    server_config.alpn_protocols = config.alpn.iter().map(|v| v.as_bytes().to_vec()).collect();
+
+    let mut certs = Vec::new();
+    for ((cert_file, _), (_, not_after)) in key_pairs(config).zip(keys) {
+        report_expiry(cert_file, not_after, config.expiry_warning);
+        certs.push((cert_file.to_string(), not_after));
+    }
+    Ok((Arc::new(server_config), certs))
+}
+
+// Returns the certified key and the expiry of the certificate.
+async fn certified_key(cert_file: &str, key_file: &str) -> Result<(Arc<CertifiedKey>, i64)> {
+    let certs = read_certs(cert_file).await?;
+    let key = read_private_key(key_file).await?;
+    verify_key_pair(&certs[0], &key).context(format!(
+        "invalid TLS cert and key files: {cert_file}, {key_file}"
+    ))?;
+    let not_after =
+        not_after(&certs[0]).context(format!("invalid certificate file: {cert_file}"))?;
+    let key =
+        sign::any_supported_type(&key).context(format!("unsupported private key: {key_file}"))?;
+    Ok((Arc::new(CertifiedKey::new(certs, key)), not_after))
+}
+
+fn cipher_suites(names: &[String]) -> Result<Vec<SupportedCipherSuite>> {
+    if names.is_empty() {
+        return Ok(DEFAULT_CIPHER_SUITES.to_vec());
+    }
+    names
+        .iter()
+        .map(|name| {
+            ALL_CIPHER_SUITES
+                .iter()
+                .find(|v| format!("{:?}", v.suite()) == *name)
+                .copied()
+                .ok_or_else(|| anyhow!("unsupported cipher suite: {name}"))
+        })
+        .collect()
+}
+
+fn protocol_versions(versions: &[TlsVersion]) -> Vec<&'static SupportedProtocolVersion> {
+    versions
+        .iter()
+        .map(|v| match v {
+            TlsVersion::V1_2 => &rustls::version::TLS12,
+            TlsVersion::V1_3 => &rustls::version::TLS13,
+        })
+        .collect()
+}
+
+/// Selects the certificate by the server name the client has sent, falling
+/// back to the default one.
+struct CertResolver {
+    default: Arc<CertifiedKey>,
+    by_name: HashMap<String, Arc<CertifiedKey>>,
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        let Some(name) = client_hello.server_name() else {
+            return Some(self.default.clone());
+        };
+        let name = name.to_lowercase();
+        let wildcard = name.split_once('.').map(|(_, v)| format!("*.{v}"));
+        self.by_name
+            .get(&name)
+            .or_else(|| self.by_name.get(&wildcard?))
+            .or(Some(&self.default))
+            .cloned()
+    }
+}
+
+// `with_single_cert` accepts a key that does not belong to the certificate, so
+// sign a message with the key and verify it with the certificate.
+fn verify_key_pair(cert: &Certificate, key: &PrivateKey) -> Result<()> {
+    const MESSAGE: &[u8] = b"TLS key pair check";
+    let schemes = [
+        (
+            SignatureScheme::ECDSA_NISTP256_SHA256,
+            &webpki::ECDSA_P256_SHA256,
+        ),
+        (
+            SignatureScheme::ECDSA_NISTP384_SHA384,
+            &webpki::ECDSA_P384_SHA384,
+        ),
+        (SignatureScheme::ED25519, &webpki::ED25519),
+        (
+            SignatureScheme::RSA_PSS_SHA256,
+            &webpki::RSA_PSS_2048_8192_SHA256_LEGACY_KEY,
+        ),
+    ];
+
+    let signer = sign::any_supported_type(key)
+        .context("unsupported private key")?
+        .choose_scheme(&schemes.map(|v| v.0))
+        .ok_or_else(|| anyhow!("unsupported private key type"))?;
+    let signature = signer
+        .sign(MESSAGE)
+        .context("failed to sign with the private key")?;
+    let (_, algorithm) = schemes
+        .iter()
+        .find(|v| v.0 == signer.scheme())
+        .ok_or_else(|| anyhow!("unsupported private key type"))?;
+    webpki::EndEntityCert::try_from(cert.0.as_slice())
+        .map_err(|err| anyhow!("invalid certificate: {err:?}"))?
+        .verify_signature(algorithm, MESSAGE, &signature)
+        .map_err(|_| anyhow!("private key does not match the certificate"))
+}
+
+fn not_after(cert: &Certificate) -> Result<i64> {
+    let (_, cert) = X509Certificate::from_der(&cert.0)?;
+    Ok(cert.validity().not_after.timestamp())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ExpiryLevel {
+    Valid,
+    ExpiresSoon,
+    Expired,
+}
+
+fn expiry_level(not_after: i64, warning_days: u64) -> ExpiryLevel {
+    let remaining = not_after - chrono::Utc::now().timestamp();
+    if remaining <= 0 {
+        ExpiryLevel::Expired
+    } else if remaining < warning_days as i64 * 24 * 60 * 60 {
+        ExpiryLevel::ExpiresSoon
+    } else {
+        ExpiryLevel::Valid
+    }
+}
+
+fn expiry_levels(certs: &[(String, i64)], warning_days: u64) -> HashMap<String, ExpiryLevel> {
+    certs
+        .iter()
+        .map(|(cert_file, not_after)| (cert_file.clone(), expiry_level(*not_after, warning_days)))
+        .collect()
+}
+
+fn report_expiry(cert_file: &str, not_after: i64, warning_days: u64) {
+    METRICS
+        .tls_certificate_expiry
+        .with_label_values(&[cert_file])
+        .set(not_after);
+    log_expiry(cert_file, not_after, expiry_level(not_after, warning_days));
+}
+
+fn log_expiry(cert_file: &str, not_after: i64, level: ExpiryLevel) {
+    let expiry = chrono::DateTime::from_timestamp(not_after, 0).unwrap_or_default();
+    match level {
+        ExpiryLevel::Expired => log::error!("TLS certificate has expired: {cert_file}: {expiry}"),
+        ExpiryLevel::ExpiresSoon => log::warn!(
+            "TLS certificate expires in {} days: {cert_file}: {expiry}",
+            (expiry - chrono::Utc::now()).num_days()
+        ),
+        ExpiryLevel::Valid => log::info!("TLS certificate expires at {expiry}: {cert_file}"),
+    }
+}
+
+async fn client_verifier(
+    config: &configuration::ClientAuth,
+) -> Result<Arc<dyn ClientCertVerifier>> {
+    let mut roots = RootCertStore::empty();
+    for cert in read_certs(&config.ca_file).await? {
+        roots
+            .add(&cert)
+            .context(format!("invalid client CA certificate: {}", config.ca_file))?;
+    }
+    let mut crls = Vec::new();
+    for file in &config.crl_files {
+        let pem = read_file(file).await?;
+        for crl in rustls_pemfile::crls(&mut pem.as_slice()) {
+            let crl = crl.context(format!("invalid CRL file: {file}"))?;
+            crls.push(UnparsedCertRevocationList(crl.to_vec()));
+        }
+    }
+
+    let verifier = match config.mode {
+        ClientAuthMode::Required => AllowAnyAuthenticatedClient::new(roots)
+            .with_crls(crls)
+            .map_err(|err| anyhow!("invalid CRL: {err:?}"))?
+            .boxed(),
+        ClientAuthMode::Optional => AllowAnyAnonymousOrAuthenticatedClient::new(roots)
+            .with_crls(crls)
+            .map_err(|err| anyhow!("invalid CRL: {err:?}"))?
+            .boxed(),
+    };
+    Ok(verifier)
+}
+
+async fn read_file(path: &str) -> Result<Vec<u8>> {
+    tokio::fs::read(path)
+        .await
+        .context(format!("failed to read: {path}"))
+}
+
+async fn read_certs(path: &str) -> Result<Vec<Certificate>> {
+    let pem = read_file(path).await?;
+    let certs = rustls_pemfile::certs(&mut pem.as_slice())
+        .map(|v| v.map(|v| Certificate(v.to_vec())))
+        .collect::<Result<Vec<_>, _>>()
+        .context(format!("invalid certificate file: {path}"))?;
+    if certs.is_empty() {
+        return Err(anyhow!("no certificate in: {path}"));
+    }
+    Ok(certs)
+}
+
+async fn read_private_key(path: &str) -> Result<PrivateKey> {
+    let pem = read_file(path).await?;
+    match rustls_pemfile::private_key(&mut pem.as_slice()) {
+        Ok(Some(key)) => Ok(PrivateKey(key.secret_der().to_vec())),
+        Ok(None) => Err(anyhow!("no private key in: {path}")),
+        Err(err) => Err(err).context(format!("invalid private key file: {path}")),
+    }
+}
+
+/// Performs the TLS handshake and attaches the identity of the client, if any,
+/// to every request on the connection.
+#[derive(Clone)]
+pub(super) struct ClientIdentityAcceptor {
+    inner: RustlsAcceptor,
+}
+
+impl ClientIdentityAcceptor {
+    pub(super) fn new(config: RustlsConfig) -> Self {
+        Self {
+            inner: RustlsAcceptor::new(config),
+        }
+    }
+}
+
+impl<I, S> Accept<I, S> for ClientIdentityAcceptor
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    S: Send + 'static,
+{
+    type Stream = <RustlsAcceptor as Accept<I, S>>::Stream;
+    type Service = AddExtension<S, Option<ClientIdentity>>;
+    type Future = BoxFuture<'static, io::Result<(Self::Stream, Self::Service)>>;
+
+    fn accept(&self, stream: I, service: S) -> Self::Future {
+        let acceptor = self.inner.clone();
+
+        Box::pin(async move {
+            let (stream, service) = acceptor.accept(stream, service).await?;
+            // The verifier has already validated the chain, so the first one is
+            // the client's own certificate.
+            let identity = stream
+                .get_ref()
+                .1
+                .peer_certificates()
+                .and_then(|v| v.first())
+                .and_then(|v| parse_identity(&v.0));
+            Ok((stream, Extension(identity).layer(service)))
+        })
+    }
+}
+
+fn parse_identity(der: &[u8]) -> Option<ClientIdentity> {
+    let (_, cert) = X509Certificate::from_der(der).ok()?;
+    let sans = match cert.subject_alternative_name() {
+        Ok(Some(ext)) => ext
+            .value
+            .general_names
+            .iter()
+            .filter_map(|v| match v {
+                GeneralName::DNSName(v) => Some(format!("DNS:{v}")),
+                GeneralName::RFC822Name(v) => Some(format!("email:{v}")),
+                GeneralName::URI(v) => Some(format!("URI:{v}")),
+                GeneralName::IPAddress(v) => parse_ip(v).map(|v| format!("IP:{v}")),
+                _ => None,
+            })
+            .collect(),
+        _ => Vec::new(),
+    };
+
+    Some(ClientIdentity {
+        subject: cert.subject().to_string(),
+        sans,
+    })
+}
+
+fn parse_ip(bytes: &[u8]) -> Option<std::net::IpAddr> {
+    match bytes.len() {
+        4 => <[u8; 4]>::try_from(bytes).ok().map(Into::into),
+        16 => <[u8; 16]>::try_from(bytes).ok().map(Into::into),
+        _ => None,
+    }
+}
@@ -0,0 +1,117 @@
+use crate::configuration;
+
+use std::fs::{self, Permissions};
+use std::io::ErrorKind;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use axum::Router;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto;
+use hyper_util::service::TowerToHyperService;
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+
+// Pause after a failed accept, which is likely to fail again right away if,
+// e.g., the process has run out of file descriptors.
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_millis(100);
+
+/// Serves `app` over a Unix domain socket until `shutdown` becomes true. Then,
+/// it stops accepting new connections and waits for the in-flight requests up
+/// to `grace_period`.
+pub(super) async fn serve(
+    config: &configuration::UnixSocket,
+    app: Router,
+    mut shutdown: watch::Receiver<bool>,
+    grace_period: Duration,
+) -> Result<()> {
+    let path = Path::new(&config.path);
+    if config.remove_stale {
+        remove_stale_socket(path).await?;
+    }
+    let listener =
+        UnixListener::bind(path).context(format!("failed to bind Unix socket: {}", config.path))?;
+    if let Some(mode) = &config.mode {
+        let mode =
+            u32::from_str_radix(mode, 8).context(format!("invalid Unix socket mode: {mode}"))?;
+        fs::set_permissions(path, Permissions::from_mode(mode)).context(format!(
+            "failed to set the permissions of Unix socket: {}",
+            config.path
+        ))?;
+    }
+
+    let mut connections = JoinSet::new();
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let stream = match accepted {
+                    Ok((stream, _)) => stream,
+                    Err(err) => {
+                        log::error!("failed to accept a Unix socket connection: {err:?}");
+                        tokio::time::sleep(ACCEPT_ERROR_DELAY).await;
+                        continue;
+                    }
+                };
+                connections.spawn(serve_connection(stream, app.clone(), shutdown.clone()));
+            }
+            _ = signaled(&mut shutdown) => break,
+        }
+    }
+
+    drop(listener);
+    if let Err(err) = fs::remove_file(path) {
+        log::warn!("failed to remove Unix socket: {}: {err:?}", config.path);
+    }
+    let drain = async { while connections.join_next().await.is_some() {} };
+    if tokio::time::timeout(grace_period, drain).await.is_err() {
+        log::warn!("aborting {} Unix socket connections", connections.len());
+        connections.abort_all();
+    }
+
+    Ok(())
+}
+
+async fn serve_connection(stream: UnixStream, app: Router, mut shutdown: watch::Receiver<bool>) {
+    let builder = auto::Builder::new(TokioExecutor::new());
+    let conn =
+        builder.serve_connection_with_upgrades(TokioIo::new(stream), TowerToHyperService::new(app));
+    tokio::pin!(conn);
+
+    let result = tokio::select! {
+        result = conn.as_mut() => result,
+        _ = signaled(&mut shutdown) => {
+            conn.as_mut().graceful_shutdown();
+            conn.await
+        }
+    };
+    if let Err(err) = result {
+        log::debug!("failed to serve a Unix socket connection: {err:?}");
+    }
+}
+
+async fn signaled(shutdown: &mut watch::Receiver<bool>) {
+    // The sender is never dropped before sending true.
+    shutdown.wait_for(|v| *v).await.ok();
+}
+
+// Removes the socket file left behind by a previous process that did not exit
+// cleanly. A socket that still accepts connections is in use and kept as is.
+async fn remove_stale_socket(path: &Path) -> Result<()> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(v) => v,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err).context(format!("failed to stat: {}", path.display())),
+    };
+    if !metadata.file_type().is_socket() {
+        return Err(anyhow!("not a Unix socket: {}", path.display()));
+    }
+    if UnixStream::connect(path).await.is_ok() {
+        return Err(anyhow!("Unix socket is in use: {}", path.display()));
+    }
+
+    log::info!("removing stale Unix socket: {}", path.display());
+    fs::remove_file(path).context(format!("failed to remove: {}", path.display()))
+}
@@ -0,0 +1,565 @@
+use super::error::{Error, ErrorBody};
+use super::extract::{self, Path, Query};
+use super::AppState;
+use super::{etag, idempotency, ClientIdentity};
+use crate::core::controller::CreateUserParams as ControllerCreateUserParams;
+use crate::core::controller::DeleteUserParams as ControllerDeleteUserParams;
+use crate::core::controller::GetUserParams as ControllerGetUserParams;
+use crate::core::controller::ListUsersParams as ControllerListUsersParams;
+use crate::core::controller::PurgeDeletedUsersParams as ControllerPurgeDeletedUsersParams;
+use crate::core::controller::RestoreUserParams as ControllerRestoreUserParams;
+use crate::core::controller::UpdateUserParams as ControllerUpdateUserParams;
+use crate::core::entity::{
+    Conflict, DatabaseTransaction, User, UserFilter, UserSort, UserSortField,
+};
+use crate::core::secret::Secret;
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::extract::State;
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::{Extension, Json};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+use tokio::time::MissedTickBehavior;
+use utoipa::{IntoParams, ToSchema};
+
+/// A user as exposed by the v2 API, i.e., without the password.
+#[derive(Serialize, ToSchema)]
+pub(super) struct UserBody {
+    id: u64,
+    username: String,
+    age: u16,
+    address: String,
+    /// Also sent as the `ETag` header.
+    version: u64,
+    /// Set while the user is deleted, i.e., until it is restored or purged.
+    #[schema(value_type = Option<String>, format = DateTime)]
+    deleted_at: Option<DateTime<Utc>>,
+}
+
+impl From<User> for UserBody {
+    fn from(user: User) -> Self {
+        Self {
+            id: user.id,
+            username: user.username,
+            age: user.age,
+            address: user.address,
+            version: user.version,
+            deleted_at: user.deleted_at,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+#[schema(as = v2::CreateUserParams)]
+pub(super) struct CreateUserParams {
+    username: String,
+    #[schema(value_type = String, format = Password)]
+    password: Secret<String>,
+    age: u16,
+    address: String,
+}
+
+impl From<CreateUserParams> for ControllerCreateUserParams {
+    fn from(params: CreateUserParams) -> Self {
+        Self {
+            username: params.username,
+            password: params.password,
+            age: params.age,
+            address: params.address,
+        }
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v2/users",
+    operation_id = "create_user_v2",
+    tag = "v2",
+    request_body = CreateUserParams,
+    params((
+        "idempotency-key" = Option<String>,
+        Header,
+        description = "Replays the first response to the retries with the same key",
+    )),
+    responses(
+        (
+            status = 201,
+            description = "Created user",
+            body = UserBody,
+            headers(
+                ("location" = String, description = "URL of the created user"),
+                ("etag" = String, description = "Version of the user"),
+            ),
+        ),
+        (status = 400, description = "Invalid request or idempotency key", body = ErrorBody),
+        (
+            status = 422,
+            description = "Idempotency key used for another request",
+            body = ErrorBody,
+        ),
+        (status = 500, description = "Internal error", body = ErrorBody),
+    ),
+)]
+pub(super) async fn create_user<T>(
+    State(state): State<Arc<AppState<T>>>,
+    headers: HeaderMap,
+    extract::Json(payload): extract::Json<CreateUserParams>,
+) -> Result<Response, Error>
+where
+    T: DatabaseTransaction + Send + Sync,
+{
+    log::debug!("create_user invoked");
+    let operation = "POST /api/v2/users";
+    if let Some(key) = idempotency::key(&headers, operation, &payload, state.idempotency_ttl)? {
+        let respond = {
+            let key = key.clone();
+            move |user: &User| {
+                let headers = [
+                    (header::LOCATION, location(user.id)),
+                    (header::ETAG, etag::etag(user.version)),
+                ];
+                let body = UserBody::from(user.clone());
+                idempotency::store(&key, StatusCode::CREATED, &headers, &body)
+            }
+        };
+        let result = state
+            .controller
+            .create_user_once(payload, key, respond)
+            .await
+            .map_err(|err| Error::internal("create a user", err))?;
+        return Ok(idempotency::respond(result));
+    }
+
+    let user = state
+        .controller
+        .create_user(payload)
+        .await
+        .map_err(|err| Error::internal("create a user", err))?;
+    Ok((
+        StatusCode::CREATED,
+        [(header::LOCATION, location(user.id))],
+        with_etag(user),
+    )
+        .into_response())
+}
+
+fn location(id: u64) -> String {
+    format!("/api/v2/users/{id}")
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub(super) struct GetUserQuery {
+    /// Returns the user even if it is deleted. Requires a client certificate.
+    include_deleted: Option<bool>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v2/users/{id}",
+    operation_id = "get_user_v2",
+    tag = "v2",
+    params(
+        ("id" = u64, Path, description = "Id of the user"),
+        GetUserQuery,
+        (
+            "if-none-match" = Option<String>,
+            Header,
+            description = "Returns 304 if the user still has one of these versions",
+        ),
+    ),
+    responses(
+        (
+            status = 200,
+            description = "User",
+            body = UserBody,
+            headers(("etag" = String, description = "Version of the user")),
+        ),
+        (status = 304, description = "User not modified"),
+        (status = 400, description = "Invalid request", body = ErrorBody),
+        (
+            status = 403,
+            description = "Deleted user requested without a client certificate",
+            body = ErrorBody,
+        ),
+        (status = 404, description = "User not found", body = ErrorBody),
+        (status = 500, description = "Internal error", body = ErrorBody),
+    ),
+)]
+pub(super) async fn get_user<T>(
+    State(state): State<Arc<AppState<T>>>,
+    Path(id): Path<u64>,
+    Query(query): Query<GetUserQuery>,
+    identity: Option<Extension<Option<ClientIdentity>>>,
+    headers: HeaderMap,
+) -> Result<Response, Error>
+where
+    T: DatabaseTransaction + Send + Sync,
+{
+    log::debug!("get_user invoked");
+    let params = ControllerGetUserParams {
+        id,
+        include_deleted: include_deleted(query.include_deleted, identity)?,
+    };
+    match state.controller.get_user(params).await {
+        Ok(Some(user)) if etag::if_none_match(&headers, user.version) => Ok((
+            StatusCode::NOT_MODIFIED,
+            [(header::ETAG, etag::etag(user.version))],
+        )
+            .into_response()),
+        Ok(Some(user)) => Ok(with_etag(user).into_response()),
+        Ok(None) => Err(Error::not_found(id)),
+        Err(err) => Err(Error::internal("get a user", err)),
+    }
+}
+
+// Only the clients authenticated with a certificate see the deleted users, as
+// the soft deletion would hide nothing otherwise.
+fn include_deleted(
+    requested: Option<bool>,
+    identity: Option<Extension<Option<ClientIdentity>>>,
+) -> Result<bool, Error> {
+    match (requested.unwrap_or_default(), identity) {
+        (false, _) => Ok(false),
+        (true, Some(Extension(Some(_)))) => Ok(true),
+        (true, _) => Err(Error::new(
+            StatusCode::FORBIDDEN,
+            "include_deleted requires a client certificate",
+        )),
+    }
+}
+
+/// Only the fields present in the body are updated.
+#[derive(Debug, Deserialize, ToSchema)]
+pub(super) struct UpdateUserParams {
+    username: Option<String>,
+    #[schema(value_type = Option<String>, format = Password)]
+    password: Option<Secret<String>>,
+    age: Option<u16>,
+    address: Option<String>,
+}
+
+#[utoipa::path(
+    patch,
+    path = "/api/v2/users/{id}",
+    operation_id = "update_user_v2",
+    tag = "v2",
+    params(
+        ("id" = u64, Path, description = "Id of the user"),
+        (
+            "if-match" = Option<String>,
+            Header,
+            description = "Updates the user only if it still has this version",
+        ),
+    ),
+    request_body = UpdateUserParams,
+    responses(
+        (
+            status = 200,
+            description = "User",
+            body = UserBody,
+            headers(("etag" = String, description = "Version of the user")),
+        ),
+        (status = 400, description = "Invalid request", body = ErrorBody),
+        (status = 404, description = "User not found", body = ErrorBody),
+        (status = 412, description = "User modified since read", body = ErrorBody),
+        (status = 500, description = "Internal error", body = ErrorBody),
+    ),
+)]
+pub(super) async fn update_user<T>(
+    State(state): State<Arc<AppState<T>>>,
+    Path(id): Path<u64>,
+    headers: HeaderMap,
+    extract::Json(payload): extract::Json<UpdateUserParams>,
+) -> Result<Response, Error>
+where
+    T: DatabaseTransaction + Send + Sync,
+{
+    log::debug!("update_user invoked");
+    let params = ControllerUpdateUserParams {
+        id,
+        username: payload.username,
+        password: payload.password,
+        age: payload.age,
+        address: payload.address,
+        expected_version: etag::if_match(&headers)?,
+    };
+    match state.controller.update_user(params).await {
+        Ok(Some(user)) => Ok(with_etag(user).into_response()),
+        Ok(None) => Err(Error::not_found(id)),
+        Err(err) => Err(write_error("update a user", err)),
+    }
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/v2/users/{id}",
+    operation_id = "delete_user_v2",
+    tag = "v2",
+    params(
+        ("id" = u64, Path, description = "Id of the user"),
+        (
+            "if-match" = Option<String>,
+            Header,
+            description = "Deletes the user only if it still has this version",
+        ),
+    ),
+    responses(
+        (status = 204, description = "Deleted, i.e., hidden until restored or purged"),
+        (status = 400, description = "Invalid request", body = ErrorBody),
+        (status = 404, description = "User not found", body = ErrorBody),
+        (status = 412, description = "User modified since read", body = ErrorBody),
+        (status = 500, description = "Internal error", body = ErrorBody),
+    ),
+)]
+pub(super) async fn delete_user<T>(
+    State(state): State<Arc<AppState<T>>>,
+    Path(id): Path<u64>,
+    headers: HeaderMap,
+) -> Result<StatusCode, Error>
+where
+    T: DatabaseTransaction + Send + Sync,
+{
+    log::debug!("delete_user invoked");
+    let params = ControllerDeleteUserParams {
+        id,
+        expected_version: etag::if_match(&headers)?,
+    };
+    match state.controller.delete_user(params).await {
+        Ok(true) => Ok(StatusCode::NO_CONTENT),
+        Ok(false) => Err(Error::not_found(id)),
+        Err(err) => Err(write_error("delete a user", err)),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v2/users/{id}/restore",
+    operation_id = "restore_user_v2",
+    tag = "v2",
+    params(
+        ("id" = u64, Path, description = "Id of the user"),
+        (
+            "if-match" = Option<String>,
+            Header,
+            description = "Restores the user only if it still has this version",
+        ),
+    ),
+    responses(
+        (
+            status = 200,
+            description = "Restored user, or the user as is if not deleted",
+            body = UserBody,
+            headers(("etag" = String, description = "Version of the user")),
+        ),
+        (status = 400, description = "Invalid request", body = ErrorBody),
+        (status = 404, description = "User not found", body = ErrorBody),
+        (status = 412, description = "User modified since read", body = ErrorBody),
+        (status = 500, description = "Internal error", body = ErrorBody),
+    ),
+)]
+pub(super) async fn restore_user<T>(
+    State(state): State<Arc<AppState<T>>>,
+    Path(id): Path<u64>,
+    headers: HeaderMap,
+) -> Result<Response, Error>
+where
+    T: DatabaseTransaction + Send + Sync,
+{
+    log::debug!("restore_user invoked");
+    let params = ControllerRestoreUserParams {
+        id,
+        expected_version: etag::if_match(&headers)?,
+    };
+    match state.controller.restore_user(params).await {
+        Ok(Some(user)) => Ok(with_etag(user).into_response()),
+        Ok(None) => Err(Error::not_found(id)),
+        Err(err) => Err(write_error("restore a user", err)),
+    }
+}
+
+/// Permanently deletes the users deleted more than `retention` ago every
+/// `interval` until shutdown.
+pub(super) async fn purge_deleted<T>(
+    state: Arc<AppState<T>>,
+    retention: Duration,
+    interval: Duration,
+    mut shutdown: watch::Receiver<bool>,
+) where
+    T: DatabaseTransaction + Send + Sync,
+{
+    let mut interval = tokio::time::interval(interval);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = shutdown.changed() => return,
+        }
+        let params = ControllerPurgeDeletedUsersParams {
+            retention: retention.as_secs(),
+        };
+        match state.controller.purge_deleted_users(params).await {
+            Ok(0) => {}
+            Ok(v) => log::info!("purged deleted users: count = {v}"),
+            Err(err) => log::error!("failed to purge deleted users: {err:?}"),
+        }
+    }
+}
+
+fn with_etag(user: User) -> impl IntoResponse {
+    (
+        [(header::ETAG, etag::etag(user.version))],
+        Json(UserBody::from(user)),
+    )
+}
+
+// A conflict means that the version of If-Match is stale.
+fn write_error(action: &str, err: anyhow::Error) -> Error {
+    match err.downcast_ref::<Conflict>() {
+        Some(conflict) => {
+            log::info!("rejected a stale write: {conflict}");
+            Error::new(
+                StatusCode::PRECONDITION_FAILED,
+                "user has been modified since read",
+            )
+        }
+        None => Error::internal(action, err),
+    }
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub(super) struct ListUsersQuery {
+    /// `next_cursor` of the previous page. The filters and the sort order have
+    /// to be the same as for it.
+    cursor: Option<String>,
+    limit: Option<u32>,
+    /// Lists the users whose username starts with this.
+    username_prefix: Option<String>,
+    min_age: Option<u16>,
+    max_age: Option<u16>,
+    /// Lists the users whose address contains this.
+    address: Option<String>,
+    /// Lists the deleted users too. Requires a client certificate.
+    include_deleted: Option<bool>,
+    #[param(inline)]
+    sort: Option<SortOrder>,
+}
+
+/// Sort order of the users. `-` stands for the descending order, and the ties
+/// are broken by the id.
+#[derive(Debug, Clone, Copy, Default, Deserialize, ToSchema)]
+pub(super) enum SortOrder {
+    #[default]
+    #[serde(rename = "id")]
+    IdAsc,
+    #[serde(rename = "-id")]
+    IdDesc,
+    #[serde(rename = "username")]
+    UsernameAsc,
+    #[serde(rename = "-username")]
+    UsernameDesc,
+    #[serde(rename = "age")]
+    AgeAsc,
+    #[serde(rename = "-age")]
+    AgeDesc,
+}
+
+impl From<SortOrder> for UserSort {
+    fn from(order: SortOrder) -> Self {
+        let (field, descending) = match order {
+            SortOrder::IdAsc => (UserSortField::Id, false),
+            SortOrder::IdDesc => (UserSortField::Id, true),
+            SortOrder::UsernameAsc => (UserSortField::Username, false),
+            SortOrder::UsernameDesc => (UserSortField::Username, true),
+            SortOrder::AgeAsc => (UserSortField::Age, false),
+            SortOrder::AgeDesc => (UserSortField::Age, true),
+        };
+        Self { field, descending }
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub(super) struct UserPage {
+    users: Vec<UserBody>,
+    /// Null on the last page.
+    next_cursor: Option<String>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v2/users",
+    operation_id = "list_users_v2",
+    tag = "v2",
+    params(ListUsersQuery),
+    responses(
+        (status = 200, description = "Page of users", body = UserPage),
+        (status = 400, description = "Invalid query", body = ErrorBody),
+        (
+            status = 403,
+            description = "Deleted users requested without a client certificate",
+            body = ErrorBody,
+        ),
+        (status = 500, description = "Internal error", body = ErrorBody),
+    ),
+)]
+pub(super) async fn list_users<T>(
+    State(state): State<Arc<AppState<T>>>,
+    Query(query): Query<ListUsersQuery>,
+    identity: Option<Extension<Option<ClientIdentity>>>,
+) -> Result<Json<UserPage>, Error>
+where
+    T: DatabaseTransaction + Send + Sync,
+{
+    log::debug!("list_users invoked");
+    let limit = query.limit.unwrap_or(state.default_page_size);
+    if limit == 0 || limit > state.max_page_size {
+        return Err(Error::new(
+            StatusCode::BAD_REQUEST,
+            format!("limit has to be between 1 and {}", state.max_page_size),
+        ));
+    }
+    let filter = UserFilter {
+        username_prefix: query.username_prefix,
+        min_age: query.min_age,
+        max_age: query.max_age,
+        address_contains: query.address,
+        include_deleted: include_deleted(query.include_deleted, identity)?,
+    };
+    let sort = UserSort::from(query.sort.unwrap_or_default());
+    let after = match &query.cursor {
+        Some(v) => Some(state.cursors.decode(v, &sort, &filter)?),
+        None => None,
+    };
+
+    // Fetch one more to know whether there is a next page.
+    let params = ControllerListUsersParams {
+        filter: filter.clone(),
+        sort,
+        after,
+        limit: limit + 1,
+    };
+    let mut users = state
+        .controller
+        .list_users(params)
+        .await
+        .map_err(|err| Error::internal("list users", err))?;
+    let next_cursor = if users.len() > limit as usize {
+        users.truncate(limit as usize);
+        users
+            .last()
+            .map(|v| state.cursors.encode(&sort.key(v), &sort, &filter))
+    } else {
+        None
+    };
+
+    Ok(Json(UserPage {
+        users: users.into_iter().map(UserBody::from).collect(),
+        next_cursor,
+    }))
+}
@@ -0,0 +1,62 @@
+use crate::configuration;
+use crate::logger::context::BoxedLayer;
+
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::trace::{Sampler, SdkTracerProvider};
+use opentelemetry_sdk::Resource;
+use tracing_subscriber::filter::filter_fn;
+use tracing_subscriber::Layer;
+
+/// Exports the spans to an OpenTelemetry collector using OTLP over HTTP.
+pub struct Exporter {
+    provider: SdkTracerProvider,
+}
+
+impl Exporter {
+    pub fn new(config: &configuration::Telemetry) -> Result<Self> {
+        let exporter = SpanExporter::builder()
+            .with_http()
+            .with_endpoint(&config.endpoint)
+            .with_timeout(Duration::from_secs(config.timeout))
+            .build()
+            .context(format!(
+                "failed to create an OTLP exporter: {}",
+                config.endpoint
+            ))?;
+        let provider = SdkTracerProvider::builder()
+            .with_batch_exporter(exporter)
+            .with_sampler(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(
+                config.sampling_ratio,
+            ))))
+            .with_resource(
+                Resource::builder()
+                    .with_service_name(config.service_name.clone())
+                    .build(),
+            )
+            .build();
+
+        Ok(Self { provider })
+    }
+
+    /// Returns a tracing layer that feeds the spans of this crate into this exporter.
+    pub fn layer(&self) -> BoxedLayer {
+        let tracer = self.provider.tracer(env!("CARGO_PKG_NAME"));
+        let filter = filter_fn(|metadata| metadata.target().starts_with(env!("CARGO_CRATE_NAME")));
+        Box::new(
+            tracing_opentelemetry::layer()
+                .with_tracer(tracer)
+                .with_filter(filter),
+        )
+    }
+
+    /// Flushes the pending spans and stops the exporter.
+    pub fn shutdown(&self) -> Result<()> {
+        self.provider
+            .shutdown()
+            .context("failed to shutdown the OTLP exporter")
+    }
+}
@@ -0,0 +1,24 @@
+use rust_base::server::http::ApiDoc;
+
+use std::fs;
+
+use utoipa::OpenApi;
+
+const SPEC_PATH: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/openapi.json");
+
+// Fails when the handlers have changed without regenerating the committed spec,
+// which can be done with `UPDATE_OPENAPI=1 cargo test --test openapi`.
+#[test]
+fn spec_is_up_to_date() {
+    let spec = ApiDoc::openapi().to_pretty_json().unwrap() + "\n";
+    if std::env::var_os("UPDATE_OPENAPI").is_some() {
+        fs::write(SPEC_PATH, &spec).unwrap();
+        return;
+    }
+
+    let committed = fs::read_to_string(SPEC_PATH).unwrap_or_default();
+    assert!(
+        committed == spec,
+        "openapi.json is out of date; run `UPDATE_OPENAPI=1 cargo test --test openapi`"
+    );
+}
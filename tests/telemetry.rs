@@ -0,0 +1,86 @@
+use rust_base::configuration::Telemetry;
+use rust_base::telemetry::Exporter;
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::Registry;
+
+// A collector stand-in, which accepts an OTLP/HTTP request and reports its path
+// and body.
+fn collector() -> (String, mpsc::Receiver<(String, Vec<u8>)>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let endpoint = format!("http://{}/v1/traces", listener.local_addr().unwrap());
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            let path = line.split(' ').nth(1).unwrap_or_default().to_string();
+            let mut length = 0;
+            loop {
+                line.clear();
+                reader.read_line(&mut line).unwrap();
+                if line.trim().is_empty() {
+                    break;
+                }
+                if let Some((name, value)) = line.split_once(':') {
+                    if name.eq_ignore_ascii_case("content-length") {
+                        length = value.trim().parse().unwrap();
+                    }
+                }
+            }
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\nconnection: close\r\n\r\n")
+                .unwrap();
+            if tx.send((path, body)).is_err() {
+                return;
+            }
+        }
+    });
+    (endpoint, rx)
+}
+
+#[test]
+fn spans_are_exported_to_collector() {
+    let (endpoint, requests) = collector();
+    let exporter = Exporter::new(&Telemetry {
+        endpoint,
+        service_name: String::from("exporter-test"),
+        sampling_ratio: 1.0,
+        timeout: 5,
+    })
+    .unwrap();
+
+    let subscriber = Registry::default().with(exporter.layer());
+    tracing::subscriber::with_default(subscriber, || {
+        // The layer only exports the spans of this crate.
+        let span = tracing::info_span!(target: "rust_base::test", "exported_span", user_id = 7);
+        span.in_scope(|| {});
+    });
+    exporter.shutdown().unwrap();
+
+    let (path, body) = requests.recv_timeout(Duration::from_secs(10)).unwrap();
+    assert_eq!(path, "/v1/traces");
+    let contains = |needle: &[u8]| body.windows(needle.len()).any(|v| v == needle);
+    assert!(
+        contains(b"exported_span"),
+        "span is missing from the payload"
+    );
+    assert!(
+        contains(b"exporter-test"),
+        "service name is missing from the payload"
+    );
+    assert!(
+        contains(b"user_id"),
+        "span attribute is missing from the payload"
+    );
+}